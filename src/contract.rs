@@ -1,41 +1,123 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
-use cosmwasm_std::{Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult};
-// use cw2::set_contract_version;
+use cosmwasm_std::{Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response};
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
+use crate::lending::{self, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, SudoMsg};
 
-/*
-// version info for migration info
-const CONTRACT_NAME: &str = "crates.io:cosmwasm";
-const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
-*/
+// the exported wasm entry points, each delegating to its handler in the lending module
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
-    _deps: DepsMut,
-    _env: Env,
-    _info: MessageInfo,
-    _msg: InstantiateMsg,
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
-    unimplemented!()
+    lending::instantiate(deps, env, info, msg)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
-    _deps: DepsMut,
-    _env: Env,
-    _info: MessageInfo,
-    _msg: ExecuteMsg,
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
-    unimplemented!()
+    lending::execute(deps, env, info, msg)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(_deps: Deps, _env: Env, _msg: QueryMsg) -> StdResult<Binary> {
-    unimplemented!()
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    lending::query(deps, env, msg)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    lending::migrate(deps, env, msg)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    lending::reply(deps, env, msg)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    lending::sudo(deps, env, msg)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use cosmwasm_std::{coins, Addr, Decimal, Empty, Uint128};
+    use cw_multi_test::{App, Contract, ContractWrapper, Executor};
+
+    use super::*;
+    use crate::lending::AccrualMode;
+
+    fn lending_contract() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(execute, instantiate, query)
+            .with_reply(reply)
+            .with_sudo(sudo)
+            .with_migrate(migrate);
+        Box::new(contract)
+    }
+
+    #[test]
+    fn exported_entry_points_drive_a_borrow_through_its_reply() {
+        let owner = Addr::unchecked("owner");
+        let alice = Addr::unchecked("alice");
+        let mut app = App::new(|router, _, storage| {
+            router.bank.init_balance(storage, &owner, coins(1_000, "usdc")).unwrap();
+            router.bank.init_balance(storage, &alice, coins(1_000, "atom")).unwrap();
+        });
+        let code_id = app.store_code(lending_contract());
+        let msg = InstantiateMsg {
+            owner: owner.to_string(),
+            base_interest_rate: Decimal::percent(5),
+            max_ltv: Decimal::percent(75),
+            liquidation_threshold: Decimal::percent(80),
+            borrow_denom: "usdc".to_string(),
+            loan_term_seconds: 0,
+            penalty_rate: Decimal::zero(),
+            reserve_factor: Decimal::zero(),
+            slope: Decimal::zero(),
+            max_borrow_per_user: Uint128::zero(),
+            min_collateral_amount: Uint128::zero(),
+            treasury: "treasury".to_string(),
+            recovery_grace_seconds: 0,
+            origination_fee: Decimal::zero(),
+            dust_threshold: Uint128::zero(),
+            borrow_cooldown_seconds: 0,
+            compound: false,
+            liquidation_bonus: Decimal::percent(5),
+            grace_interest_free_seconds: 0,
+            price_max_age_seconds: 0,
+            min_loan_seconds: 0,
+            max_loan_seconds: 0,
+            close_factor: Decimal::zero(),
+            accrual_mode: AccrualMode::Time,
+            oracle: None,
+        };
+        let contract = app.instantiate_contract(code_id, owner.clone(), &msg, &[], "lending", Some(owner.to_string())).unwrap();
+
+        for msg in [
+            ExecuteMsg::AllowToken { token_address: "atom".to_string() },
+            ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::one() },
+        ] {
+            app.execute_contract(owner.clone(), contract.clone(), &msg, &[]).unwrap();
+        }
+        app.execute_contract(owner.clone(), contract.clone(), &ExecuteMsg::ProvideLiquidity {}, &coins(1_000, "usdc")).unwrap();
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(1_000) };
+        app.execute_contract(alice.clone(), contract.clone(), &msg, &coins(1_000, "atom")).unwrap();
+
+        // the payout is sent with reply_always, so the borrow only succeeds if the reply export is wired up
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        app.execute_contract(alice.clone(), contract.clone(), &msg, &[]).unwrap();
+        assert_eq!(app.wrap().query_balance(&alice, "usdc").unwrap().amount, Uint128::new(500));
+
+        let version: crate::lending::VersionResponse = app.wrap().query_wasm_smart(&contract, &QueryMsg::Version {}).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+        app.migrate_contract(owner, contract, &MigrateMsg {}, code_id).unwrap();
+    }
+}
@@ -1,13 +1,150 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{
+    CheckedFromRatioError, CheckedMultiplyFractionError, Decimal, OverflowError, StdError, Uint128,
+};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq)]
 pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
     #[error("Unauthorized")]
     Unauthorized {},
+
+    #[error("Amount cannot be zero")]
+    ZeroAmount {},
+
+    #[error("Insufficient collateral or mismatched token address")]
+    InsufficientCollateral {},
+
+    #[error("Cannot withdraw: remaining borrowing power {remaining} would not cover outstanding debt {borrowed}")]
+    CollateralLocked { remaining: Uint128, borrowed: Uint128 },
+
+    #[error("No active loan for this borrower")]
+    NoActiveLoan {},
+
+    #[error("Borrow amount exceeds the loan-to-value limit (max borrow {max_borrow})")]
+    LtvExceeded { max_borrow: Uint128 },
+
+    #[error("Borrower already has an outstanding loan")]
+    LoanAlreadyExists {},
+
+    #[error("Must attach exactly {amount}{denom}")]
+    FundsMismatch { denom: String, amount: Uint128 },
+
+    #[error("Must attach at least {amount}{denom}")]
+    InsufficientFunds { denom: String, amount: Uint128 },
+
+    #[error("Loan is sufficiently collateralized and cannot be liquidated")]
+    LoanHealthy {},
+
+    #[error("Contract is paused")]
+    ContractPaused {},
+
+    #[error("Only the pending owner can accept ownership")]
+    NotPendingOwner {},
+
+    #[error("No loan transfer from {from} is pending for this address")]
+    NotPendingBorrower { from: String },
+
+    #[error("{0}")]
+    Overflow(#[from] OverflowError),
+
+    #[error("{0}")]
+    CheckedFromRatio(#[from] CheckedFromRatioError),
+
+    #[error("{0}")]
+    CheckedMultiplyFraction(#[from] CheckedMultiplyFractionError),
+
+    #[error("Denom cannot be empty")]
+    InvalidDenom {},
+
+    #[error("Cannot migrate from a different contract ({name})")]
+    InvalidContractName { name: String },
+
+    #[error("Cannot migrate from newer version {stored} to {current}")]
+    CannotDowngrade { stored: String, current: String },
+
+    #[error("Collateral factor must be between 0 and 1")]
+    InvalidCollateralFactor {},
+
+    #[error("Close factor must be between 0 and 1")]
+    InvalidCloseFactor {},
+
+    #[error("Percentage must be between 0 and 100%")]
+    InvalidPercentage {},
+
+    #[error("Token {token_address} is not accepted as collateral")]
+    TokenNotAllowed { token_address: String },
+
+    #[error("Reserve factor must be between 0 and 1")]
+    InvalidReserveFactor {},
+
+    #[error("Origination fee must be between 0 and 1")]
+    InvalidOriginationFee {},
+
+    #[error("Withdrawal exceeds protocol reserves (available {available})")]
+    InsufficientReserves { available: Uint128 },
+
+    #[error("Reentrant call rejected while another operation is in flight")]
+    Reentrancy {},
+
+    #[error("Unknown reply id {id}")]
+    UnknownReplyId { id: u64 },
+
+    #[error("Borrow amount exceeds the per-user limit of {limit}")]
+    BorrowLimitExceeded { limit: Uint128 },
+
+    #[error("Deposit is below the minimum collateral amount of {min}")]
+    DepositBelowMinimum { min: Uint128 },
+
+    #[error("Batch exceeds the maximum of {max} entries")]
+    BatchTooLarge { max: u32 },
+
+    #[error("Loan is not past its term plus the recovery grace period")]
+    LoanNotStale {},
+
+    #[error("Interest rate cannot exceed {max}")]
+    InvalidInterestRate { max: Decimal },
+
+    #[error("Token decimals cannot exceed {max}")]
+    InvalidDecimals { max: u8 },
+
+    #[error("Loan balance is not below the dust threshold of {threshold}")]
+    LoanNotDust { threshold: Uint128 },
+
+    #[error("Must attach a non-zero amount of a single denom")]
+    InvalidLiquidityDeposit {},
+
+    #[error("Insufficient liquidity, only {available} available")]
+    InsufficientLiquidity { available: Uint128 },
+
+    #[error("Owner address cannot be empty")]
+    EmptyOwner {},
+
+    #[error("Borrow cooldown active until {ready_at}")]
+    BorrowCooldown { ready_at: u64 },
+
+    #[error("Config update must change at least one field")]
+    EmptyConfigUpdate {},
+
+    #[error("No collateral deposited")]
+    NoCollateral {},
+
+    #[error("Liquidation bonus cannot exceed {max}")]
+    InvalidLiquidationBonus { max: Decimal },
+
+    #[error("Loan is underwater and cannot be extended")]
+    LoanUnderwater {},
+
+    #[error("Loan duration must be between {min} and {max} seconds (0 means unbounded)")]
+    InvalidLoanDuration { min: u64, max: u64 },
+
+    #[error("Borrower still has collateral; liquidate before writing off")]
+    CollateralRemaining {},
+
+    #[error("Refinancing needs a rate below the current {current}, but {offered} is offered")]
+    RefinanceNotBeneficial { current: Decimal, offered: Decimal },
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
@@ -1,9 +1,25 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{to_json_binary, Addr, CosmosMsg, StdResult, WasmMsg};
+use cosmwasm_std::{
+    to_json_binary, Addr, Coin, CosmosMsg, Decimal, OverflowError, OverflowOperation, StdError,
+    StdResult, Uint128, Uint256, WasmMsg,
+};
 
-use crate::msg::ExecuteMsg;
+use crate::lending::{AccrualMode, Config, ExecuteMsg, LoanInfo, RateMode};
+
+/// Seconds in a (non-leap) year, used to prorate annual interest rates.
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
+
+/// Nominal block time used by [`AccrualMode::BlockHeight`]: each block accrues
+/// `annual_rate / BLOCKS_PER_YEAR`, the same as this many seconds would.
+pub const SECONDS_PER_BLOCK: u64 = 6;
+
+/// Blocks in a year at [`SECONDS_PER_BLOCK`].
+pub const BLOCKS_PER_YEAR: u64 = SECONDS_PER_YEAR / SECONDS_PER_BLOCK;
+
+/// Interest is compounded once a day when `Config::compound` is set.
+pub const COMPOUNDING_PERIOD: u64 = 86_400;
 
 /// CwTemplateContract is a wrapper around Addr that provides a lot of helpers
 /// for working with this.
@@ -25,3 +41,498 @@ impl CwTemplateContract {
         .into())
     }
 }
+
+/// A validated `Coin` to pay out of the contract. Zero amounts and empty denoms
+/// are rejected so no empty or malformed bank send is ever emitted.
+pub fn make_payout(amount: Uint128, denom: &str) -> StdResult<Coin> {
+    if amount.is_zero() {
+        return Err(StdError::generic_err("Payout amount cannot be zero"));
+    }
+    if denom.trim().is_empty() {
+        return Err(StdError::generic_err("Payout denom cannot be empty"));
+    }
+    Ok(Coin::new(amount.u128(), denom))
+}
+
+/// `amount * rate` rounded down, reporting overflow instead of panicking.
+pub fn mul_decimal(amount: Uint128, rate: Decimal) -> Result<Uint128, OverflowError> {
+    amount
+        .checked_mul_floor(rate)
+        .map_err(|_| OverflowError::new(OverflowOperation::Mul, amount, rate))
+}
+
+/// `numerator / denominator` rounded up. Interest is always rounded this way so
+/// fractional units are collected by the protocol instead of forgiven to the
+/// borrower. Panics if `denominator` is zero.
+pub fn div_ceil(numerator: Uint256, denominator: Uint256) -> Uint256 {
+    let quotient = numerator / denominator;
+    if (numerator % denominator).is_zero() {
+        quotient
+    } else {
+        quotient + Uint256::one()
+    }
+}
+
+/// `amount * annual_rate` prorated over `seconds`, rounded up with [`div_ceil`].
+/// The product is taken in 256 bits so nothing is truncated before the division.
+fn prorated_interest(
+    amount: Uint128,
+    annual_rate: Decimal,
+    seconds: u64,
+) -> Result<Uint128, OverflowError> {
+    let numerator = Uint256::from(amount)
+        .checked_mul(Uint256::from(annual_rate.atomics()))?
+        .checked_mul(Uint256::from(seconds))?;
+    let denominator = Uint256::from(Decimal::one().atomics()) * Uint256::from(SECONDS_PER_YEAR);
+    div_ceil(numerator, denominator)
+        .try_into()
+        .map_err(|_| OverflowError::new(OverflowOperation::Mul, amount, annual_rate))
+}
+
+/// `amount * annual_rate` compounded every [`COMPOUNDING_PERIOD`] over `seconds`,
+/// with simple interest for the final partial period. Rounded up.
+fn compound_interest(
+    amount: Uint128,
+    annual_rate: Decimal,
+    seconds: u64,
+) -> Result<Uint128, OverflowError> {
+    let overflow = || OverflowError::new(OverflowOperation::Mul, amount, annual_rate);
+    let periods = u32::try_from(seconds / COMPOUNDING_PERIOD).map_err(|_| overflow())?;
+    let period_growth = Decimal::one().checked_add(
+        annual_rate.checked_mul(Decimal::from_ratio(COMPOUNDING_PERIOD, SECONDS_PER_YEAR))?,
+    )?;
+    let partial_growth = Decimal::one().checked_add(annual_rate.checked_mul(
+        Decimal::from_ratio(seconds % COMPOUNDING_PERIOD, SECONDS_PER_YEAR),
+    )?)?;
+    let growth = period_growth
+        .checked_pow(periods)?
+        .checked_mul(partial_growth)?;
+    let total = amount.checked_mul_ceil(growth).map_err(|_| overflow())?;
+    total.checked_sub(amount)
+}
+
+/// The annual rate `loan` accrues at: its opening rate if fixed, or the
+/// current `config.base_interest_rate` if floating.
+pub fn loan_rate(loan: &LoanInfo, config: &Config) -> Decimal {
+    match loan.rate_mode {
+        RateMode::Fixed => loan.interest_rate,
+        RateMode::Floating => config.base_interest_rate,
+    }
+}
+
+/// Term of `loan` in seconds: the duration chosen when it was opened, or
+/// `config.loan_term_seconds` for loans opened without one. 0 means no term.
+pub fn loan_term(loan: &LoanInfo, config: &Config) -> u64 {
+    if loan.term_seconds != 0 {
+        loan.term_seconds
+    } else {
+        config.loan_term_seconds
+    }
+}
+
+/// Timestamp the term of `loan` runs from: `loan.term_start_time`, or
+/// `loan.loan_start_time` for loans stored before it was tracked. Unlike the
+/// start time, partial repayments do not move it.
+pub fn term_start(loan: &LoanInfo) -> u64 {
+    if loan.term_start_time != 0 {
+        loan.term_start_time
+    } else {
+        loan.loan_start_time
+    }
+}
+
+/// Interest accrued on `loan` up to `now` at [`loan_rate`], prorated by the
+/// seconds elapsed since the later of its start and `loan.interest_free_until`
+/// and compounded if `config.compound` is set, plus simple `config.penalty_rate`
+/// interest for the time since its start that is past the end of the loan term
+/// (see [`term_start`]). Rounded up.
+///
+/// Under [`AccrualMode::BlockHeight`] the blocks since `loan.loan_start_height`
+/// stand in for the elapsed seconds, each counting as [`SECONDS_PER_BLOCK`], less
+/// the length of the interest-free window. Loans opened before heights were
+/// recorded (`loan_start_height == 0`) keep accruing by time. The overdue
+/// penalty is always measured in time.
+pub fn accrued_interest(
+    loan: &LoanInfo,
+    config: &Config,
+    now: u64,
+    height: u64,
+) -> Result<Uint128, OverflowError> {
+    let accruing = match config.accrual_mode {
+        AccrualMode::BlockHeight if loan.loan_start_height != 0 => height
+            .saturating_sub(loan.loan_start_height)
+            .saturating_mul(SECONDS_PER_BLOCK)
+            .saturating_sub(
+                loan.interest_free_until
+                    .saturating_sub(loan.loan_start_time),
+            ),
+        _ => now.saturating_sub(loan.loan_start_time.max(loan.interest_free_until)),
+    };
+    let rate = loan_rate(loan, config);
+    let interest = if rate.is_zero() {
+        // nothing to prorate or compound at 0%
+        Uint128::zero()
+    } else if config.compound {
+        compound_interest(loan.amount_borrowed, rate, accruing)?
+    } else {
+        prorated_interest(loan.amount_borrowed, rate, accruing)?
+    };
+    let term = loan_term(loan, config);
+    let due = term_start(loan).saturating_add(term);
+    if term == 0 || now <= due {
+        return Ok(interest);
+    }
+
+    let overdue = now - due.max(loan.loan_start_time);
+    interest.checked_add(prorated_interest(
+        loan.amount_borrowed,
+        config.penalty_rate,
+        overdue,
+    )?)
+}
+
+/// Principal plus accrued interest owed on `loan` at `now` and `height`.
+pub fn total_due(
+    loan: &LoanInfo,
+    config: &Config,
+    now: u64,
+    height: u64,
+) -> Result<Uint128, OverflowError> {
+    loan.amount_borrowed
+        .checked_add(accrued_interest(loan, config, now, height)?)
+}
+
+/// Rescale `amount` from a token with `from_decimals` to one with `to_decimals`,
+/// rounding down when precision is dropped.
+pub fn normalize_amount(
+    amount: Uint128,
+    from_decimals: u8,
+    to_decimals: u8,
+) -> Result<Uint128, OverflowError> {
+    if from_decimals >= to_decimals {
+        let scale = Uint128::new(10).checked_pow((from_decimals - to_decimals) as u32)?;
+        Ok(amount / scale)
+    } else {
+        let scale = Uint128::new(10).checked_pow((to_decimals - from_decimals) as u32)?;
+        amount.checked_mul(scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            owner: Addr::unchecked("owner"),
+            base_interest_rate: Decimal::percent(5),
+            max_ltv: Decimal::percent(75),
+            liquidation_threshold: Decimal::percent(80),
+            paused: false,
+            pending_owner: None,
+            borrow_denom: "usdc".to_string(),
+            loan_term_seconds: 0,
+            penalty_rate: Decimal::zero(),
+            reserve_factor: Decimal::zero(),
+            slope: Decimal::zero(),
+            max_borrow_per_user: Uint128::zero(),
+            min_collateral_amount: Uint128::zero(),
+            treasury: Addr::unchecked("treasury"),
+            recovery_grace_seconds: 0,
+            origination_fee: Decimal::zero(),
+            dust_threshold: Uint128::zero(),
+            borrow_cooldown_seconds: 0,
+            compound: false,
+            liquidation_bonus: Decimal::percent(5),
+            grace_interest_free_seconds: 0,
+            price_max_age_seconds: 0,
+            min_loan_seconds: 0,
+            max_loan_seconds: 0,
+            close_factor: Decimal::zero(),
+            accrual_mode: AccrualMode::Time,
+            oracle: None,
+        }
+    }
+
+    fn loan(amount: u128) -> LoanInfo {
+        LoanInfo {
+            amount_borrowed: Uint128::new(amount),
+            interest_rate: Decimal::percent(10),
+            loan_start_time: 1_000,
+            loan_start_height: 100,
+            rate_mode: RateMode::Fixed,
+            interest_free_until: 0,
+            term_seconds: 0,
+            term_start_time: 0,
+        }
+    }
+
+    #[test]
+    fn make_payout_builds_coin() {
+        assert_eq!(
+            make_payout(Uint128::new(42), "usdc").unwrap(),
+            Coin::new(42, "usdc")
+        );
+    }
+
+    #[test]
+    fn make_payout_rejects_zero_amount_and_empty_denom() {
+        assert_eq!(
+            make_payout(Uint128::zero(), "usdc").unwrap_err(),
+            StdError::generic_err("Payout amount cannot be zero")
+        );
+        for denom in ["", "  "] {
+            assert_eq!(
+                make_payout(Uint128::new(1), denom).unwrap_err(),
+                StdError::generic_err("Payout denom cannot be empty")
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_amount_rescales_both_ways() {
+        let one_eth = Uint128::new(1_000_000_000_000_000_000);
+        assert_eq!(
+            normalize_amount(one_eth, 18, 6).unwrap(),
+            Uint128::new(1_000_000)
+        );
+        assert_eq!(
+            normalize_amount(Uint128::new(1_000_000), 6, 18).unwrap(),
+            one_eth
+        );
+        assert_eq!(
+            normalize_amount(Uint128::new(999), 6, 3).unwrap(),
+            Uint128::zero()
+        );
+        assert_eq!(
+            normalize_amount(Uint128::new(42), 6, 6).unwrap(),
+            Uint128::new(42)
+        );
+    }
+
+    #[test]
+    fn total_due_with_no_elapsed_time_is_principal() {
+        assert_eq!(
+            total_due(&loan(1_000_000), &config(), 1_000, 100).unwrap(),
+            Uint128::new(1_000_000)
+        );
+        // a clock before the loan start is treated as no time elapsed
+        assert_eq!(
+            total_due(&loan(1_000_000), &config(), 0, 100).unwrap(),
+            Uint128::new(1_000_000)
+        );
+    }
+
+    #[test]
+    fn total_due_after_one_year() {
+        let now = 1_000 + SECONDS_PER_YEAR;
+        assert_eq!(
+            total_due(&loan(1_000_000), &config(), now, 100).unwrap(),
+            Uint128::new(1_100_000)
+        );
+    }
+
+    #[test]
+    fn total_due_prorates_partial_periods() {
+        let now = 1_000 + SECONDS_PER_YEAR / 4;
+        assert_eq!(
+            total_due(&loan(1_000_000), &config(), now, 100).unwrap(),
+            Uint128::new(1_025_000)
+        );
+        // 0.175 of interest is rounded up in the protocol's favor
+        assert_eq!(
+            total_due(&loan(7), &config(), now, 100).unwrap(),
+            Uint128::new(8)
+        );
+    }
+
+    #[test]
+    fn zero_rate_accrues_nothing() {
+        let free = LoanInfo {
+            interest_rate: Decimal::zero(),
+            ..loan(1_000_000)
+        };
+        let now = 1_000 + SECONDS_PER_YEAR * 3;
+        for compound in [false, true] {
+            let config = Config {
+                compound,
+                ..config()
+            };
+            assert_eq!(
+                total_due(&free, &config, now, 100).unwrap(),
+                Uint128::new(1_000_000)
+            );
+        }
+    }
+
+    #[test]
+    fn div_ceil_rounds_up_only_remainders() {
+        let ceil = |n: u128, d: u128| div_ceil(Uint256::from(n), Uint256::from(d));
+        assert_eq!(ceil(10, 5), Uint256::from(2u128));
+        assert_eq!(ceil(11, 5), Uint256::from(3u128));
+        assert_eq!(ceil(0, 5), Uint256::zero());
+    }
+
+    #[test]
+    fn interest_never_under_collects() {
+        let year = SECONDS_PER_YEAR as u128;
+        for amount in [1u128, 7, 999, 123_456_789] {
+            for elapsed in [1u64, 59, 3_600, 86_399, SECONDS_PER_YEAR / 3] {
+                let interest = accrued_interest(&loan(amount), &config(), 1_000 + elapsed, 100)
+                    .unwrap()
+                    .u128();
+                // exact interest is amount * 10% * elapsed / year
+                let exact_numerator = amount * elapsed as u128;
+                let scaled = interest * 10 * year;
+                assert!(scaled >= exact_numerator, "{amount} over {elapsed}s");
+                assert!(
+                    scaled < exact_numerator + 10 * year,
+                    "{amount} over {elapsed}s"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compound_interest_exceeds_simple_over_a_year() {
+        let loan = LoanInfo {
+            interest_rate: Decimal::percent(5),
+            ..loan(1_000_000)
+        };
+        let now = 1_000 + SECONDS_PER_YEAR;
+        let simple = total_due(&loan, &config(), now, 100).unwrap();
+        let compound = Config {
+            compound: true,
+            ..config()
+        };
+        let compounded = total_due(&loan, &compound, now, 100).unwrap();
+        assert_eq!(simple, Uint128::new(1_050_000));
+        // 1_000_000 * (1 + 0.05 / 365) ^ 365
+        assert_eq!(compounded, Uint128::new(1_051_268));
+        assert!(compounded > simple);
+
+        // within the first period both models agree
+        let now = 1_000 + COMPOUNDING_PERIOD / 2;
+        assert_eq!(
+            total_due(&loan, &compound, now, 100).unwrap(),
+            total_due(&loan, &config(), now, 100).unwrap()
+        );
+    }
+
+    #[test]
+    fn floating_loans_follow_the_base_rate() {
+        let floating = LoanInfo {
+            rate_mode: RateMode::Floating,
+            ..loan(1_000_000)
+        };
+        let now = 1_000 + SECONDS_PER_YEAR;
+        // the fixed loan keeps its 10%, the floating one accrues at the base 5%
+        assert_eq!(
+            total_due(&loan(1_000_000), &config(), now, 100).unwrap(),
+            Uint128::new(1_100_000)
+        );
+        assert_eq!(
+            total_due(&floating, &config(), now, 100).unwrap(),
+            Uint128::new(1_050_000)
+        );
+    }
+
+    #[test]
+    fn no_interest_accrues_in_the_free_window() {
+        let loan = LoanInfo {
+            interest_free_until: 1_000 + SECONDS_PER_YEAR / 2,
+            ..loan(1_000_000)
+        };
+        let free_end = 1_000 + SECONDS_PER_YEAR / 2;
+        assert_eq!(
+            total_due(&loan, &config(), free_end, 100).unwrap(),
+            Uint128::new(1_000_000)
+        );
+        // only the half year past the window accrues
+        assert_eq!(
+            total_due(&loan, &config(), 1_000 + SECONDS_PER_YEAR, 100).unwrap(),
+            Uint128::new(1_050_000)
+        );
+    }
+
+    #[test]
+    fn loan_term_overrides_config_term() {
+        let config = Config {
+            loan_term_seconds: SECONDS_PER_YEAR,
+            penalty_rate: Decimal::percent(20),
+            ..config()
+        };
+        let short = LoanInfo {
+            term_seconds: SECONDS_PER_YEAR / 2,
+            ..loan(1_000_000)
+        };
+        assert_eq!(loan_term(&loan(1_000_000), &config), SECONDS_PER_YEAR);
+        assert_eq!(loan_term(&short, &config), SECONDS_PER_YEAR / 2);
+        // the half year past the shorter term is penalized
+        assert_eq!(
+            total_due(&short, &config, 1_000 + SECONDS_PER_YEAR, 100).unwrap(),
+            Uint128::new(1_000_000 + 100_000 + 100_000)
+        );
+    }
+
+    #[test]
+    fn penalty_runs_from_the_term_start() {
+        let config = Config {
+            loan_term_seconds: SECONDS_PER_YEAR,
+            penalty_rate: Decimal::percent(20),
+            ..config()
+        };
+        // restarted accrual half a year in, but the term still ends a year after 1_000
+        let repaid = LoanInfo {
+            loan_start_time: 1_000 + SECONDS_PER_YEAR / 2,
+            term_start_time: 1_000,
+            ..loan(1_000_000)
+        };
+        assert_eq!(term_start(&repaid), 1_000);
+        assert_eq!(term_start(&loan(1_000_000)), 1_000);
+        assert_eq!(
+            total_due(&repaid, &config, 1_000 + SECONDS_PER_YEAR * 3 / 2, 100).unwrap(),
+            Uint128::new(1_000_000 + 100_000 + 100_000)
+        );
+    }
+
+    #[test]
+    fn block_mode_accrues_by_elapsed_blocks() {
+        let config = Config {
+            accrual_mode: AccrualMode::BlockHeight,
+            ..config()
+        };
+        // a year's worth of blocks accrues a year of interest, whatever the clock says
+        assert_eq!(
+            total_due(&loan(1_000_000), &config, 1_000, 100 + BLOCKS_PER_YEAR).unwrap(),
+            Uint128::new(1_100_000)
+        );
+        assert_eq!(
+            total_due(&loan(1_000_000), &config, 1_000 + SECONDS_PER_YEAR, 100).unwrap(),
+            Uint128::new(1_000_000)
+        );
+        // loans without a recorded start height fall back to time
+        let legacy = LoanInfo {
+            loan_start_height: 0,
+            ..loan(1_000_000)
+        };
+        assert_eq!(
+            total_due(&legacy, &config, 1_000 + SECONDS_PER_YEAR, 100).unwrap(),
+            Uint128::new(1_100_000)
+        );
+    }
+
+    #[test]
+    fn total_due_adds_penalty_past_term() {
+        let config = Config {
+            loan_term_seconds: SECONDS_PER_YEAR,
+            penalty_rate: Decimal::percent(20),
+            ..config()
+        };
+        let now = 1_000 + SECONDS_PER_YEAR * 3 / 2;
+        assert_eq!(
+            total_due(&loan(1_000_000), &config, now, 100).unwrap(),
+            Uint128::new(1_000_000 + 150_000 + 100_000)
+        );
+    }
+}
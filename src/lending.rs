@@ -1,52 +1,548 @@
 //import libs
 use cosmwasm_std::{
-    BankMsg, coin, Decimal, DepsMut, entry_point, Env, MessageInfo, Response, StdError, StdResult, Uint128
+    Addr, BankMsg, Binary, coin, CosmosMsg, Decimal, Deps, DepsMut, Env, Event, MessageInfo, Order, 
+    Reply, Response, StdError, StdResult, Storage, SubMsg, SubMsgResult, from_json, to_json_binary, Uint128, WasmMsg
 };
-use cw_storage_plus::{Item, Map};
+use cw2::{CONTRACT, set_contract_version};
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw_storage_plus::{Bound, Item, Map};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 
+use crate::error::ContractError;
+use crate::helpers::{loan_rate, loan_term, make_payout, mul_decimal, normalize_amount, term_start, total_due};
+
 // define init message struct
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct InstantiateMsg {
     pub owner: String, // 合約擁有者地址
     pub base_interest_rate: Decimal, // 基礎年利率
+    pub max_ltv: Decimal, // 最高借款成數 (loan-to-value)
+    pub liquidation_threshold: Decimal, // 清算門檻
+    pub borrow_denom: String, // 借出的幣別
+    pub loan_term_seconds: u64, // 借款期限（秒），0 表示無期限
+    pub penalty_rate: Decimal, // 逾期罰息年利率
+    pub reserve_factor: Decimal, // 利息中撥入協議準備金的比例
+    pub slope: Decimal, // 資金利用率達 100% 時在基礎利率之上加收的年利率
+    pub max_borrow_per_user: Uint128, // 單一借款人借款上限，0 表示無上限
+    pub min_collateral_amount: Uint128, // 單次存入抵押品的最低數量
+    pub treasury: String, // 協議金庫地址
+    pub recovery_grace_seconds: u64, // 逾期超過此秒數後擁有者可回收抵押品
+    pub origination_fee: Decimal, // 借款手續費率，從撥款中扣除
+    pub dust_threshold: Uint128, // 欠款低於此數量時擁有者可強制結清
+    pub borrow_cooldown_seconds: u64, // 同一借款人兩次借款之間的最短間隔（秒），0 表示不限制
+    pub compound: bool, // 是否以複利計息，預設為單利
+    pub liquidation_bonus: Decimal, // 清算獎勵，以償還欠款的比例計，最高 20%
+    pub grace_interest_free_seconds: u64, // 借款後免息的秒數，0 表示無免息期
+    pub price_max_age_seconds: u64, // 預言機價格快取的有效秒數
+    pub min_loan_seconds: u64, // 借款時可指定的最短期限（秒）
+    pub max_loan_seconds: u64, // 借款時可指定的最長期限（秒），未指定期限時以此為準，0 表示不限制
+    pub close_factor: Decimal, // 單次清算最多可償還的欠款比例，0 表示可一次清算全部
+    pub accrual_mode: AccrualMode, // 計息依據：區塊時間或區塊高度，預設為時間
+    pub oracle: Option<String>, // 價格預言機合約地址，未設定時抵押品以 1:1 計價
+}
+
+// define config update message struct, fields left as None keep their current value
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct UpdateConfigMsg {
+    pub max_ltv: Option<Decimal>, // 最高借款成數
+    pub liquidation_threshold: Option<Decimal>, // 清算門檻
+    pub loan_term_seconds: Option<u64>, // 借款期限（秒）
+    pub penalty_rate: Option<Decimal>, // 逾期罰息年利率
+    pub reserve_factor: Option<Decimal>, // 利息中撥入協議準備金的比例
+    pub slope: Option<Decimal>, // 利用率加成斜率
+    pub max_borrow_per_user: Option<Uint128>, // 單一借款人借款上限
+    pub min_collateral_amount: Option<Uint128>, // 單次存入抵押品的最低數量
+    pub treasury: Option<String>, // 協議金庫地址
+    pub recovery_grace_seconds: Option<u64>, // 回收抵押品的寬限期（秒）
+    pub origination_fee: Option<Decimal>, // 借款手續費率
+    pub dust_threshold: Option<Uint128>, // 零頭借款門檻
+    pub borrow_cooldown_seconds: Option<u64>, // 借款冷卻時間（秒）
+    pub compound: Option<bool>, // 是否以複利計息
+    pub liquidation_bonus: Option<Decimal>, // 清算獎勵比例
+    pub grace_interest_free_seconds: Option<u64>, // 借款後免息的秒數
+    pub price_max_age_seconds: Option<u64>, // 預言機價格快取的有效秒數
+    pub min_loan_seconds: Option<u64>, // 最短借款期限（秒）
+    pub max_loan_seconds: Option<u64>, // 最長借款期限（秒）
+    pub close_factor: Option<Decimal>, // 單次清算可償還的欠款比例
+    pub oracle: Option<String>, // 價格預言機合約地址
 }
 
+// define migrate message struct
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigrateMsg {}
+
 // define contract supported operations
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum ExecuteMsg {
     DepositCollateral { token_address: String, amount: Uint128 }, // 存入抵押品
     WithdrawCollateral { token_address: String, amount: Uint128 }, // 取出抵押品
-    Borrow { amount: Uint128 }, // 借款
-    RepayLoan { amount: Uint128 }, // 還款
+    WithdrawCollateralPct { token_address: String, pct: Decimal }, // 依比例取出抵押品，pct 最高為 1（100%）
+    Borrow { denom: String, amount: Uint128, rate_mode: Option<RateMode>, duration: Option<u64>, request_id: Option<String> }, // 以任一有流動性的幣別借款，額度依價格換算成 borrow_denom 計算；未指定利率模式時為固定利率，未指定期限時為 max_loan_seconds；重送相同 request_id 不會重複借款
+    RepayLoan { denom: String, amount: Uint128 }, // 償還指定幣別的借款
+    UpdateInterestRate { new_rate: Decimal }, // 更新基礎年利率（僅限擁有者）
+    Liquidate { borrower: String, denom: String }, // 清算抵押不足的借款人在指定幣別的借款
+    SetPaused { paused: bool }, // 暫停／恢復合約（僅限擁有者）
+    ProposeNewOwner { new_owner: String }, // 提名新擁有者（僅限擁有者）
+    AcceptOwnership {}, // 被提名者接受擁有權
+    SetCollateralFactor { token_address: String, factor: Decimal }, // 設定抵押品折算率（僅限擁有者）
+    SetTokenDecimals { token_address: String, decimals: u8 }, // 設定代幣小數位數（僅限擁有者）
+    AllowToken { token_address: String }, // 將代幣加入抵押品白名單（僅限擁有者）
+    DisallowToken { token_address: String }, // 將代幣移出抵押品白名單（僅限擁有者）
+    Receive(Cw20ReceiveMsg), // CW20 代幣 send 進合約時的回呼
+    WithdrawReserves { amount: Uint128, denom: Option<String> }, // 提領指定幣別（預設 borrow_denom）的協議準備金（僅限擁有者）
+    SetMinCollateralAmount { amount: Uint128 }, // 設定單次存入抵押品的最低數量（僅限擁有者）
+    BatchRepay { denom: String, repayments: Vec<(String, Uint128)> }, // 一次替多位借款人償還指定幣別的借款 (borrower, amount)
+    RecoverStaleCollateral { borrower: String }, // 將長期逾期借款的抵押品回收至金庫（僅限擁有者）
+    CloseDustLoan { borrower: String, denom: String }, // 結清零頭借款，餘額由準備金吸收（僅限擁有者）
+    ProvideLiquidity {}, // 存入單一幣別可供借出的流動性（僅限擁有者）
+    RemoveLiquidity { amount: Uint128, denom: Option<String> }, // 取回指定幣別（預設 borrow_denom）尚未借出的流動性（僅限擁有者）
+    UpdateConfig(Box<UpdateConfigMsg>), // 一次更新多項設定參數（僅限擁有者）
+    SwapCollateral { from_token: String, to_token: String, from_amount: Uint128, to_amount: Uint128 }, // 以附上的 to_token 換回部分 from_token 抵押品
+    SetLiquidationBonus { bonus: Decimal }, // 設定清算獎勵比例（僅限擁有者）
+    ExtendLoan { denom: String }, // 付清目前應計利息後，從現在起重新計算借款期限
+    SelfLiquidate {}, // 以自己的抵押品清償所有借款，剩餘抵押品退還，不收清算獎勵
+    Ping {}, // 不讀寫任何狀態，回傳 pong 與目前區塊高度，供部署後的冒煙測試使用
+    WriteOffBadDebt { borrower: String }, // 沖銷抵押品已全數清算後仍未償還的欠款（僅限擁有者）
+    TransferLoan { new_borrower: String }, // 更換錢包時，提議將自己所有借款與抵押品移轉到新地址，須由新地址接受
+    AcceptLoanTransfer { from: String }, // 接受 from 提議移轉過來的借款與抵押品
+    RepayFromReserves { borrower: String }, // 以協議準備金代為結清借款人的 borrow_denom 借款（僅限擁有者）
+    SetTokenRate { token_address: String, rate: Option<Decimal> }, // 設定以某抵押品借款時取代基礎利率的利率，None 則移除（僅限擁有者）
+    Refinance {}, // 付清 borrow_denom 借款目前應計利息後，改以較低的目前利率重新計息
+    BatchLiquidate { limit: Option<u32> }, // 一次清算最多 limit 筆可清算的借款（上限 20 筆），以附上的資金償還，剩餘退還；無法清算的借款會被略過並回報
+}
+
+// privileged operations invoked by the chain itself (e.g. through governance)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SudoMsg {
+    SetBaseRate { rate: Decimal }, // 更新基礎年利率
+}
+
+// messages embedded in a CW20 send to this contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReceiveMsg {
+    DepositCollateral {}, // 以收到的 CW20 代幣作為抵押品
+}
+
+// define contract supported queries
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum QueryMsg {
+    GetLoan { borrower: String, denom: String }, // 查詢借款人在指定幣別的借款
+    GetCollateral { borrower: String }, // 查詢抵押品
+    GetConfig {}, // 查詢合約設定
+    ListLoans { start_after: Option<(String, String)>, limit: Option<u32>, denom: Option<String> }, // 分頁列出所有借款，游標為 (borrower, denom)；指定 denom 時只列出該幣別
+    GetStats { denom: Option<String> }, // 查詢協議整體統計，借款與利息總額為指定幣別（預設 borrow_denom）
+    HealthFactor { borrower: String }, // 查詢借款人健康係數
+    ListCollaterals { start_after: Option<(String, String)>, limit: Option<u32> }, // 分頁列出所有抵押品，游標為 (borrower, token_address)
+    GetOwner {}, // 查詢合約擁有者
+    IsOwner { address: String }, // 查詢地址是否為合約擁有者
+    SimulateBorrow { borrower: String, denom: String, amount: Uint128 }, // 試算借款是否可行及其利率、健康係數
+    RateHistory { start_after: Option<u64>, limit: Option<u32> }, // 分頁列出基礎利率變動紀錄
+    Reserves { denom: Option<String> }, // 查詢指定幣別（預設 borrow_denom）的協議準備金
+    BorrowCapacity { borrower: String }, // 查詢借款人還能再借多少
+    LiquidatableLoans { limit: Option<u32> }, // 列出健康係數低於 1、可被清算的借款
+    WithdrawableCollateral { borrower: String, token_address: String }, // 查詢某抵押品目前可安全取出的數量
+    SolvencyCheck {}, // 檢查協議整體抵押品是否足以涵蓋所有欠款
+    LoanCount {}, // 查詢目前借款筆數
+    CollateralCount {}, // 查詢目前抵押品筆數
+    CurrentBorrowRate { borrower: Option<String>, denom: Option<String> }, // 查詢此刻指定幣別（預設 borrow_denom）新借款會採用的年利率，指定借款人時套用其抵押品的利率覆寫
+    Version {}, // 查詢 cw2 記錄的合約名稱與版本
+    CollateralByToken {}, // 查詢各抵押代幣在所有用戶間的存入總量
+}
+
+// query understood by the price oracle contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleQueryMsg {
+    Price { denom: String }, // 查詢一單位抵押品折合多少 borrow_denom
+}
+
+// price oracle response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OraclePriceResponse {
+    pub price: Decimal, //value of one unit of the token in borrow_denom, after decimal normalization
+}
+
+// loan query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoanResponse {
+    pub amount_borrowed: Uint128, //borrowed amount
+    pub interest_rate: Decimal, //interest rate
+    pub loan_start_time: u64, //loan start time
+    pub loan_start_height: u64, //loan start block height
+    pub accrued_interest: Uint128, //interest accrued up to the queried block
+    pub total_due: Uint128, //amount_borrowed + accrued_interest
+    pub rate_mode: RateMode, //fixed or floating
+    pub is_overdue: bool, //past its term, so penalty interest is accruing; always false without a term
+    pub seconds_until_due: Option<u64>, //seconds left in the term, 0 once overdue; None without a term
+}
+
+// collateral query response, one entry per deposited token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CollateralResponse {
+    pub collaterals: Vec<Collateral>, //collateral positions
+}
+
+// list loans query response, (borrower, denom, loan) ordered by borrower then denom
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoansResponse {
+    pub loans: Vec<(String, String, LoanInfo)>, //loans
+}
+
+// per-token collateral totals query response, (token_address, amount) ordered by token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CollateralByTokenResponse {
+    pub totals: Vec<(String, Uint128)>, //collateral held for all users of each token
+}
+
+// list collaterals query response, (borrower, token_address, amount) ordered by borrower then token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CollateralsResponse {
+    pub collaterals: Vec<(String, String, Uint128)>, //collateral positions
+}
+
+// protocol stats query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StatsResponse {
+    pub total_borrowed: Uint128, //outstanding principal across all loans
+    pub total_collateral: Uint128, //collateral held across all users
+    pub base_interest_rate: Decimal, //current base rate
+    pub total_interest_collected: Uint128, //interest paid over the contract's lifetime, reserves included
+    pub total_bad_debt: Uint128, //debt written off over the contract's lifetime
+}
+
+// health factor query response, below 1 means the loan can be liquidated
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HealthFactorResponse {
+    pub health_factor: Decimal, //Decimal::MAX when the borrower has no debt
+}
+
+// simulate borrow query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SimulateBorrowResponse {
+    pub allowed: bool, //whether the borrow would currently succeed
+    pub error: Option<String>, //why it would be rejected
+    pub interest_rate: Decimal, //rate the loan would be opened at
+    pub health_factor: Decimal, //health factor right after borrowing
+}
+
+// rate history query response, (timestamp, new base rate) ordered by time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateHistoryResponse {
+    pub history: Vec<(u64, Decimal)>, //rate changes
+}
+
+// contract version query response, as recorded by cw2
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VersionResponse {
+    pub contract: String, //contract name
+    pub version: String, //contract version, bumped by migrate
+}
+
+// loan / collateral count query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CountResponse {
+    pub count: u64, //number of records
+}
+
+// current borrow rate query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BorrowRateResponse {
+    pub rate: Decimal, //annual rate a loan opened now would be snapshotted at
+    pub utilization: Decimal, //share of the liquidity pool currently lent out
+}
+
+// reserves query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReservesResponse {
+    pub reserves: Uint128, //protocol reserves available to withdraw, in borrow_denom
+    pub reserve_factor: Decimal, //share of repaid interest credited to reserves
+}
+
+// liquidatable loans query response, (borrower, denom, health_factor) ordered by borrower then denom
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LiquidatableLoansResponse {
+    pub loans: Vec<(String, String, Decimal)>, //loans whose borrower's health factor is below 1
+}
+
+// solvency check query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SolvencyResponse {
+    pub solvent: bool, //total_collateral_value * liquidation_threshold >= total_outstanding_debt
+    pub total_collateral_value: Uint128, //collateral held across all users, priced and weighted in borrow_denom units
+    pub total_outstanding_debt: Uint128, //principal plus accrued interest across all loans
+}
+
+// withdrawable collateral query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawableCollateralResponse {
+    pub amount: Uint128, //amount of the token that can be withdrawn without the loans losing their backing
+}
+
+// borrow capacity query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BorrowCapacityResponse {
+    pub capacity: Uint128, //collateral value * max_ltv - outstanding debt, floored at zero
+}
+
+// owner query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OwnerResponse {
+    pub owner: Addr, //擁有者地址
+}
+
+// is-owner query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IsOwnerResponse {
+    pub is_owner: bool, //whether the queried address is the owner
+}
+
+// config query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConfigResponse {
+    pub owner: Addr, //擁有者地址
+    pub base_interest_rate: Decimal, //基礎年利率
+    pub max_ltv: Decimal, //最高借款成數
+    pub liquidation_threshold: Decimal, //清算門檻
+    pub paused: bool, //是否暫停
+    pub pending_owner: Option<Addr>, //待接受的新擁有者
+    pub borrow_denom: String, //借出的幣別
+    pub loan_term_seconds: u64, //借款期限（秒）
+    pub penalty_rate: Decimal, //逾期罰息年利率
+    pub reserve_factor: Decimal, //利息撥入準備金的比例
+    pub slope: Decimal, //利用率利率斜率
+    pub max_borrow_per_user: Uint128, //單一借款人借款上限
+    pub min_collateral_amount: Uint128, //單次存入抵押品的最低數量
+    pub treasury: Addr, //協議金庫地址
+    pub recovery_grace_seconds: u64, //逾期後可回收抵押品的寬限期（秒）
+    pub origination_fee: Decimal, //借款手續費率
+    pub dust_threshold: Uint128, //零頭借款門檻
+    pub borrow_cooldown_seconds: u64, //兩次借款之間的冷卻時間（秒）
+    pub compound: bool, //是否以複利計息
+    pub liquidation_bonus: Decimal, //清算獎勵比例
+    pub grace_interest_free_seconds: u64, //借款後免息的秒數
+    pub price_max_age_seconds: u64, //預言機價格快取的有效秒數
+    pub min_loan_seconds: u64, //最短借款期限（秒）
+    pub max_loan_seconds: u64, //最長借款期限（秒）
+    pub close_factor: Decimal, //單次清算可償還的欠款比例
+    pub accrual_mode: AccrualMode, //計息依據
+    pub oracle: Option<Addr>, //價格預言機合約地址
 }
 
 // config and status
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Config {
-    pub owner: String, //擁有者地址
+    pub owner: Addr, //擁有者地址
     pub base_interest_rate: Decimal, //基礎年利率
+    pub max_ltv: Decimal, //最高借款成數，借款不得超過抵押品價值 * max_ltv
+    pub liquidation_threshold: Decimal, //欠款超過抵押品價值 * liquidation_threshold 時可被清算
+    pub paused: bool, //暫停時禁止存入、取出抵押品及借款，僅允許還款
+    pub pending_owner: Option<Addr>, //已提名但尚未接受的新擁有者
+    pub borrow_denom: String, //借出的幣別，借款與還款皆以此計價
+    pub loan_term_seconds: u64, //借款期限（秒），自借款（或展期、轉貸）起超過 loan_term_seconds 即逾期，部分還款不會重新起算，0 表示無期限
+    pub penalty_rate: Decimal, //逾期期間在原利率之外額外加收的年利率
+    pub reserve_factor: Decimal, //每筆已付利息中撥入 PROTOCOL_RESERVES 的比例
+    pub slope: Decimal, //新借款利率 = base_interest_rate + slope * 資金利用率
+    pub max_borrow_per_user: Uint128, //單筆借款金額上限，0 表示無上限
+    pub min_collateral_amount: Uint128, //單次存入抵押品的最低數量，避免零碎存款塞滿 COLLATERALS
+    pub treasury: Addr, //協議金庫地址，接收回收的抵押品、借款手續費與提領的準備金
+    pub recovery_grace_seconds: u64, //借款逾期超過此寬限期仍無人清算時，擁有者可將抵押品回收至金庫
+    pub origination_fee: Decimal, //借款時從撥款中扣除並轉入金庫的比例，債務仍以全額計
+    pub dust_threshold: Uint128, //欠款（含利息）低於此數量的借款可由擁有者結清，餘額由 PROTOCOL_RESERVES 吸收
+    pub borrow_cooldown_seconds: u64, //同一借款人兩次借款之間至少需間隔的秒數，避免快速反覆借款；0 表示不限制
+    pub compound: bool, //為 true 時利息按 COMPOUNDING_PERIOD 週期複利計算，否則為單利；逾期罰息一律為單利
+    pub liquidation_bonus: Decimal, //清算人額外取得的抵押品，以所償還欠款的比例計，不得超過 MAX_LIQUIDATION_BONUS
+    pub grace_interest_free_seconds: u64, //借款後不計利息的秒數，之後才開始計息；0 表示無免息期
+    pub price_max_age_seconds: u64, //快取的預言機價格在此秒數內可直接沿用，超過則重新向預言機查詢
+    pub min_loan_seconds: u64, //借款時指定的期限不得短於此秒數
+    pub max_loan_seconds: u64, //借款時指定的期限不得長於此秒數，未指定時即採用此期限；0 表示無上限，未指定時沿用 loan_term_seconds
+    pub close_factor: Decimal, //單次清算最多可償還該筆借款欠款的比例，0 表示不限制（一次清算全部）
+    pub accrual_mode: AccrualMode, //以區塊時間或區塊高度計算經過期間，只能在初始化時設定
+    pub oracle: Option<Addr>, //提供抵押品價格的預言機合約，None 表示所有抵押品以 1:1 計價
+}
+
+// oracle price of a collateral token and the block timestamp it was fetched at
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CachedPrice {
+    pub price: Decimal, //value of one unit of the token in borrow_denom
+    pub updated_at: u64, //block timestamp of the oracle query
+}
+
+// how a loan's interest rate is determined
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateMode {
+    #[default]
+    Fixed, //interest accrues at the rate snapshotted when the loan was opened
+    Floating, //interest accrues at the base_interest_rate current when it is computed
+}
+
+// what the elapsed period of a loan is measured in when accruing interest
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AccrualMode {
+    #[default]
+    Time, //seconds of block time since the loan started
+    BlockHeight, //blocks since the loan started, each at a per-block rate of annual / BLOCKS_PER_YEAR
 }
 
 // loan info
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LoanInfo {
     pub amount_borrowed: Uint128,//borrowed amount
-    pub interest_rate: Decimal, //interest rate
+    pub interest_rate: Decimal, //interest rate at opening, used while the loan is fixed-rate
     pub loan_start_time: u64, //loan start time
+    #[serde(default)]
+    pub loan_start_height: u64, //block height the loan started at; 0 for loans stored before heights were recorded
+    #[serde(default)]
+    pub rate_mode: RateMode, //fixed or floating; loans stored before rate modes existed are fixed
+    #[serde(default)]
+    pub interest_free_until: u64, //no interest accrues before this timestamp; fixed when the loan is opened
+    #[serde(default)]
+    pub term_seconds: u64, //term chosen when borrowing; 0 falls back to config.loan_term_seconds
+    #[serde(default)]
+    pub term_start_time: u64, //timestamp the term runs from, moved only by ExtendLoan and Refinance; 0 for loans stored before it was tracked
 }
 
 // Collateral info
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Collateral {
-    pub token_address: String, //token address
+    pub token_address: String, //token address (native denom or CW20 contract)
     pub amount: Uint128, //amount
+    #[serde(default)]
+    pub cw20: bool, //whether token_address is a CW20 contract
+    #[serde(default)]
+    pub deposited_at: u64, //timestamp of the latest deposit into this position; 0 for positions stored before it was tracked
+}
+
+// an outgoing transfer awaiting its reply, with what is needed to undo the operation if it fails
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct PendingTransfer {
+    owner: Addr, //borrower or collateral owner
+    token_address: String, //denom or CW20 contract sent out
+    amount: Uint128, //amount debited from the position (for a borrow, the full debt including the fee)
+    fee: Uint128, //origination fee, sent to the treasury once the payout succeeds
+    cw20: bool, //whether token_address is a CW20 contract
+    #[serde(default)]
+    request_id: Option<String>, //idempotency key of the borrow, released if it is rolled back
+    #[serde(default)]
+    deposited_at: u64, //deposit timestamp of the collateral position being withdrawn from
+}
+
+// config as stored by earlier versions, fields added since then are optional
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct LegacyConfig {
+    owner: Addr,
+    base_interest_rate: Decimal,
+    max_ltv: Option<Decimal>,
+    liquidation_threshold: Option<Decimal>,
+    #[serde(default)]
+    paused: bool,
+    pending_owner: Option<Addr>,
+    borrow_denom: Option<String>,
+    #[serde(default)]
+    loan_term_seconds: u64,
+    #[serde(default)]
+    penalty_rate: Decimal,
+    #[serde(default)]
+    reserve_factor: Decimal,
+    #[serde(default)]
+    slope: Decimal,
+    #[serde(default)]
+    max_borrow_per_user: Uint128,
+    #[serde(default)]
+    min_collateral_amount: Uint128,
+    treasury: Option<Addr>,
+    #[serde(default)]
+    recovery_grace_seconds: u64,
+    #[serde(default)]
+    origination_fee: Decimal,
+    #[serde(default)]
+    dust_threshold: Uint128,
+    #[serde(default)]
+    borrow_cooldown_seconds: u64,
+    #[serde(default)]
+    compound: bool,
+    liquidation_bonus: Option<Decimal>,
+    #[serde(default)]
+    grace_interest_free_seconds: u64,
+    #[serde(default)]
+    price_max_age_seconds: u64,
+    #[serde(default)]
+    min_loan_seconds: u64,
+    #[serde(default)]
+    max_loan_seconds: u64,
+    #[serde(default)]
+    close_factor: Decimal,
+    #[serde(default)]
+    accrual_mode: AccrualMode,
+    #[serde(default)]
+    oracle: Option<Addr>,
 }
 
 //storage config、loan info and collateral storage。
 const CONFIG: Item<Config> = Item::new("config");
-const LOANS: Map<String, LoanInfo> = Map::new("loans");
-const COLLATERALS: Map<String, Collateral> = Map::new("collaterals");
+const LOANS: Map<(&Addr, &str), LoanInfo> = Map::new("loans"); // keyed by (borrower, denom)
+const LEGACY_LOANS: Map<&Addr, LoanInfo> = Map::new("loans"); // LOANS as stored before loans were keyed by denom, re-keyed by migrate
+const COLLATERALS: Map<(&Addr, &str), Collateral> = Map::new("collaterals"); // keyed by (owner, token_address)
+const LEGACY_COLLATERALS: Map<&Addr, Collateral> = Map::new("collaterals"); // COLLATERALS as stored before positions were keyed by token, re-keyed by migrate
+const TOTAL_BORROWED: Map<&str, Uint128> = Map::new("borrowed_totals"); // outstanding principal of all loans, per denom
+const LEGACY_TOTAL_BORROWED: Item<Uint128> = Item::new("total_borrowed"); // TOTAL_BORROWED before it was kept per denom, moved by migrate
+const TOTAL_COLLATERAL: Item<Uint128> = Item::new("total_collateral"); // collateral held for all users
+const COLLATERAL_TOTALS: Map<String, Uint128> = Map::new("collateral_totals"); // collateral held for all users, per token; kept by save_collateral / remove_collateral
+const COLLATERAL_FACTORS: Map<String, Decimal> = Map::new("collateral_factors"); // share of a token's value usable as borrowing power
+const TOKEN_RATES: Map<String, Decimal> = Map::new("token_rates"); // base rate replacing base_interest_rate for loans backed by a token
+const ALLOWED_TOKENS: Map<String, bool> = Map::new("allowed_tokens"); // whitelist of accepted collateral tokens
+const PROTOCOL_RESERVES: Map<&str, Uint128> = Map::new("reserves"); // protocol's cut of repaid interest, per denom
+const LEGACY_PROTOCOL_RESERVES: Item<Uint128> = Item::new("protocol_reserves"); // PROTOCOL_RESERVES before it was kept per denom, moved by migrate
+const TOTAL_INTEREST_COLLECTED: Map<&str, Uint128> = Map::new("interest_collected"); // cumulative interest paid by repayments and liquidations, per denom
+const LEGACY_TOTAL_INTEREST_COLLECTED: Item<Uint128> = Item::new("total_interest_collected"); // TOTAL_INTEREST_COLLECTED before it was kept per denom, moved by migrate
+const TOTAL_BAD_DEBT: Item<Uint128> = Item::new("total_bad_debt"); // cumulative debt written off after the collateral ran out, valued in borrow_denom
+const TOTAL_LIQUIDITY: Map<&str, Uint128> = Map::new("liquidity"); // lendable liquidity supplied to the pool of each denom, borrowed or not
+const LEGACY_TOTAL_LIQUIDITY: Item<Uint128> = Item::new("total_liquidity"); // TOTAL_LIQUIDITY before it was kept per denom, moved by migrate
+const REENTRANCY_LOCK: Item<bool> = Item::new("reentrancy_lock"); // held from a guarded operation until its outgoing transfers reply
+const TOKEN_DECIMALS: Map<String, u8> = Map::new("token_decimals"); // decimals of collateral tokens and the borrow denom
+const RATE_HISTORY: Map<u64, Decimal> = Map::new("rate_history"); // base rate set at each block timestamp it changed
+const LAST_BORROW: Map<&Addr, u64> = Map::new("last_borrow"); // block timestamp of each borrower's latest borrow
+const LOAN_COUNT: Item<u64> = Item::new("loan_count"); // number of entries in LOANS, kept by save_loan / remove_loan
+const COLLATERAL_COUNT: Item<u64> = Item::new("collateral_count"); // number of entries in COLLATERALS, kept by save_collateral / remove_collateral
+const PRICE_CACHE: Map<String, CachedPrice> = Map::new("price_cache"); // last oracle price fetched for each collateral token
+const PROCESSED_BORROWS: Map<(&Addr, &str), ()> = Map::new("processed_borrows"); // idempotency keys of completed borrows, scoped to the borrower
+const PENDING_LOAN_TRANSFERS: Map<&Addr, Addr> = Map::new("pending_loan_transfers"); // proposed new borrower of each borrower's loans, until accepted
+const PENDING_TRANSFER: Item<PendingTransfer> = Item::new("pending_transfer"); // cleared once the borrow / withdraw transfer replies
+
+// version info for migration info
+const CONTRACT_NAME: &str = "crates.io:cosmwasm";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// defaults backfilled into configs stored before these fields existed
+const DEFAULT_MAX_LTV: Decimal = Decimal::percent(75);
+const DEFAULT_LIQUIDATION_THRESHOLD: Decimal = Decimal::percent(80);
+const DEFAULT_BORROW_DENOM: &str = "usdc";
+const DEFAULT_LIQUIDATION_BONUS: Decimal = Decimal::percent(5);
+
+// decimals assumed for the borrow denom until the owner sets them, and the most a token may have
+const DEFAULT_DECIMALS: u8 = 6;
+const MAX_DECIMALS: u8 = 18;
+
+// highest base_interest_rate that instantiate, the owner or governance may set
+const MAX_INTEREST_RATE: Decimal = Decimal::percent(100);
+
+// highest liquidation bonus, so the owner cannot set one that strips borrowers of their collateral
+const MAX_LIQUIDATION_BONUS: Decimal = Decimal::percent(20);
+
+// reply ids of the last outgoing message of a guarded operation, each releases REENTRANCY_LOCK;
+// borrow and withdraw replies also undo the operation when the transfer failed
+const RELEASE_LOCK_REPLY_ID: u64 = 1;
+const BORROW_REPLY_ID: u64 = 2;
+const WITHDRAW_REPLY_ID: u64 = 3;
+
+// pagination limits for list queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+// most entries a single batch operation may process
+const MAX_BATCH_SIZE: usize = 20;
 
 // contract init
 pub fn instantiate(
@@ -54,167 +550,5872 @@ pub fn instantiate(
     _env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
+    // 空白的擁有者會讓所有擁有者專屬操作永久無法使用
+    if msg.owner.trim().is_empty() {
+        return Err(ContractError::EmptyOwner {});
+    }
+    if msg.borrow_denom.trim().is_empty() {
+        return Err(ContractError::InvalidDenom {});
+    }
+    if msg.reserve_factor > Decimal::one() {
+        return Err(ContractError::InvalidReserveFactor {});
+    }
+    if msg.close_factor > Decimal::one() {
+        return Err(ContractError::InvalidCloseFactor {});
+    }
+    if msg.origination_fee > Decimal::one() {
+        return Err(ContractError::InvalidOriginationFee {});
+    }
+    ensure_valid_interest_rate(msg.base_interest_rate)?;
+    ensure_valid_liquidation_bonus(msg.liquidation_bonus)?;
+    ensure_valid_loan_bounds(msg.min_loan_seconds, msg.max_loan_seconds)?;
     let config = Config {
-        owner: msg.owner,
+        owner: deps.api.addr_validate(&msg.owner)?,
         base_interest_rate: msg.base_interest_rate,
+        max_ltv: msg.max_ltv,
+        liquidation_threshold: msg.liquidation_threshold,
+        paused: false,
+        pending_owner: None,
+        borrow_denom: msg.borrow_denom,
+        loan_term_seconds: msg.loan_term_seconds,
+        penalty_rate: msg.penalty_rate,
+        reserve_factor: msg.reserve_factor,
+        slope: msg.slope,
+        max_borrow_per_user: msg.max_borrow_per_user,
+        min_collateral_amount: msg.min_collateral_amount,
+        treasury: deps.api.addr_validate(&msg.treasury)?,
+        recovery_grace_seconds: msg.recovery_grace_seconds,
+        origination_fee: msg.origination_fee,
+        dust_threshold: msg.dust_threshold,
+        borrow_cooldown_seconds: msg.borrow_cooldown_seconds,
+        compound: msg.compound,
+        liquidation_bonus: msg.liquidation_bonus,
+        grace_interest_free_seconds: msg.grace_interest_free_seconds,
+        price_max_age_seconds: msg.price_max_age_seconds,
+        min_loan_seconds: msg.min_loan_seconds,
+        max_loan_seconds: msg.max_loan_seconds,
+        close_factor: msg.close_factor,
+        accrual_mode: msg.accrual_mode,
+        oracle: msg.oracle.map(|oracle| deps.api.addr_validate(&oracle)).transpose()?,
     };
     CONFIG.save(deps.storage, &config)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     Ok(Response::new().add_attribute("method", "instantiate"))
 }
 
+// MessageInfo sent by the stored owner, so tests can exercise owner-only messages without knowing the
+// owner address; only compiled with the test-utils feature
+#[cfg(feature = "test-utils")]
+pub fn owner_info(deps: Deps, funds: &[cosmwasm_std::Coin]) -> StdResult<MessageInfo> {
+    Ok(MessageInfo { sender: CONFIG.load(deps.storage)?.owner, funds: funds.to_vec() })
+}
+
+// contract migration
+pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    // 早期版本未儲存版本資訊，視為最舊版本
+    let current = Version::parse(CONTRACT_VERSION).map_err(|e| StdError::generic_err(e.to_string()))?;
+    let previous = CONTRACT.may_load(deps.storage)?;
+    if let Some(previous) = &previous {
+        if previous.contract != CONTRACT_NAME {
+            return Err(ContractError::InvalidContractName { name: previous.contract.clone() });
+        }
+        let stored = Version::parse(&previous.version).map_err(|e| StdError::generic_err(e.to_string()))?;
+        if stored > current {
+            return Err(ContractError::CannotDowngrade { stored: previous.version.clone(), current: CONTRACT_VERSION.to_string() });
+        }
+    }
+
+    // 補上舊版設定中缺少的欄位
+    let legacy: LegacyConfig = Item::new("config").load(deps.storage)?;
+    let config = Config {
+        treasury: legacy.treasury.unwrap_or_else(|| legacy.owner.clone()),
+        owner: legacy.owner,
+        base_interest_rate: legacy.base_interest_rate,
+        max_ltv: legacy.max_ltv.unwrap_or(DEFAULT_MAX_LTV),
+        liquidation_threshold: legacy.liquidation_threshold.unwrap_or(DEFAULT_LIQUIDATION_THRESHOLD),
+        paused: legacy.paused,
+        pending_owner: legacy.pending_owner,
+        borrow_denom: legacy.borrow_denom.unwrap_or_else(|| DEFAULT_BORROW_DENOM.to_string()),
+        loan_term_seconds: legacy.loan_term_seconds,
+        penalty_rate: legacy.penalty_rate,
+        reserve_factor: legacy.reserve_factor,
+        slope: legacy.slope,
+        max_borrow_per_user: legacy.max_borrow_per_user,
+        min_collateral_amount: legacy.min_collateral_amount,
+        recovery_grace_seconds: legacy.recovery_grace_seconds,
+        origination_fee: legacy.origination_fee,
+        dust_threshold: legacy.dust_threshold,
+        borrow_cooldown_seconds: legacy.borrow_cooldown_seconds,
+        compound: legacy.compound,
+        liquidation_bonus: legacy.liquidation_bonus.unwrap_or(DEFAULT_LIQUIDATION_BONUS),
+        grace_interest_free_seconds: legacy.grace_interest_free_seconds,
+        price_max_age_seconds: legacy.price_max_age_seconds,
+        min_loan_seconds: legacy.min_loan_seconds,
+        max_loan_seconds: legacy.max_loan_seconds,
+        close_factor: legacy.close_factor,
+        accrual_mode: legacy.accrual_mode,
+        oracle: legacy.oracle,
+    };
+    CONFIG.save(deps.storage, &config)?;
+    // 舊版借款只以借款人為鍵（皆為 borrow_denom），改存為 (借款人, borrow_denom)；
+    // 新格式的鍵以長度前綴開頭，首位元組必為 0，而地址字串不會以 0 開頭
+    let legacy_loans = LEGACY_LOANS
+        .range_raw(deps.storage, None, None, Order::Ascending)
+        .filter(|entry| !matches!(entry, Ok((key, _)) if key.first() == Some(&0)))
+        .collect::<StdResult<Vec<_>>>()?;
+    for (key, loan) in legacy_loans {
+        let borrower = Addr::unchecked(String::from_utf8(key).map_err(StdError::from)?);
+        LEGACY_LOANS.remove(deps.storage, &borrower);
+        LOANS.save(deps.storage, (&borrower, config.borrow_denom.as_str()), &loan)?;
+    }
+    // 舊版抵押品同樣只以擁有者為鍵（每人一筆），依其 token_address 改存為 (擁有者, token_address)，
+    // 須在下方補算筆數與各代幣總額之前完成
+    let legacy_collaterals = LEGACY_COLLATERALS
+        .range_raw(deps.storage, None, None, Order::Ascending)
+        .filter(|entry| !matches!(entry, Ok((key, _)) if key.first() == Some(&0)))
+        .collect::<StdResult<Vec<_>>>()?;
+    for (key, collateral) in legacy_collaterals {
+        let owner = Addr::unchecked(String::from_utf8(key).map_err(StdError::from)?);
+        LEGACY_COLLATERALS.remove(deps.storage, &owner);
+        COLLATERALS.save(deps.storage, (&owner, collateral.token_address.as_str()), &collateral)?;
+    }
+    // 舊版的借款、準備金、利息與流動性總額皆為 borrow_denom，改存到以幣別為鍵的總額中
+    for (legacy, total) in [
+        (LEGACY_TOTAL_BORROWED, TOTAL_BORROWED),
+        (LEGACY_PROTOCOL_RESERVES, PROTOCOL_RESERVES),
+        (LEGACY_TOTAL_INTEREST_COLLECTED, TOTAL_INTEREST_COLLECTED),
+        (LEGACY_TOTAL_LIQUIDITY, TOTAL_LIQUIDITY),
+    ] {
+        if let Some(amount) = legacy.may_load(deps.storage)? {
+            legacy.remove(deps.storage);
+            add_to_denom_total(deps.storage, &total, &config.borrow_denom, amount)?;
+        }
+    }
+    // 舊版未維護筆數計數器，升級時一次性補算
+    if LOAN_COUNT.may_load(deps.storage)?.is_none() {
+        let count = LOANS.keys(deps.storage, None, None, Order::Ascending).count() as u64;
+        LOAN_COUNT.save(deps.storage, &count)?;
+    }
+    if COLLATERAL_COUNT.may_load(deps.storage)?.is_none() {
+        let count = COLLATERALS.keys(deps.storage, None, None, Order::Ascending).count() as u64;
+        COLLATERAL_COUNT.save(deps.storage, &count)?;
+    }
+    if COLLATERAL_TOTALS.is_empty(deps.storage) {
+        let positions = COLLATERALS.range(deps.storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+        for ((_, token_address), collateral) in positions {
+            let total = COLLATERAL_TOTALS.may_load(deps.storage, token_address.clone())?.unwrap_or_default();
+            save_token_total(deps.storage, &token_address, total.checked_add(collateral.amount)?)?;
+        }
+    }
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("previous_version", previous.map(|v| v.version).unwrap_or_default())
+        .add_attribute("version", CONTRACT_VERSION))
+}
+
 // execute contract operations
 pub fn execute(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::DepositCollateral { token_address, amount } => {
-            deposit_collateral(deps, info, token_address, amount)
+            deposit_collateral(deps, env, info, token_address, amount)
         },
         ExecuteMsg::WithdrawCollateral { token_address, amount } => {
-            withdraw_collateral(deps, info, token_address, amount)
+            withdraw_collateral(deps, env, info, token_address, amount)
+        },
+        ExecuteMsg::WithdrawCollateralPct { token_address, pct } => {
+            withdraw_collateral_pct(deps, env, info, token_address, pct)
         },
-        ExecuteMsg::Borrow { amount } => {
-            borrow(deps, env, info, amount)
+        ExecuteMsg::Borrow { denom, amount, rate_mode, duration, request_id } => {
+            borrow(deps, env, info, denom, amount, rate_mode.unwrap_or_default(), duration, request_id)
         },
-        ExecuteMsg::RepayLoan { amount } => {
-            repay_loan(deps, info, amount)
+        ExecuteMsg::RepayLoan { denom, amount } => {
+            repay_loan(deps, env, info, denom, amount)
         },
+        ExecuteMsg::UpdateInterestRate { new_rate } => {
+            update_interest_rate(deps, env, info, new_rate)
+        },
+        ExecuteMsg::Liquidate { borrower, denom } => {
+            liquidate(deps, env, info, borrower, denom)
+        },
+        ExecuteMsg::SetPaused { paused } => {
+            set_paused(deps, info, paused)
+        },
+        ExecuteMsg::ProposeNewOwner { new_owner } => {
+            propose_new_owner(deps, info, new_owner)
+        },
+        ExecuteMsg::AcceptOwnership {} => {
+            accept_ownership(deps, info)
+        },
+        ExecuteMsg::SetCollateralFactor { token_address, factor } => {
+            set_collateral_factor(deps, info, token_address, factor)
+        },
+        ExecuteMsg::SetTokenDecimals { token_address, decimals } => {
+            set_token_decimals(deps, info, token_address, decimals)
+        },
+        ExecuteMsg::AllowToken { token_address } => {
+            set_token_allowed(deps, info, token_address, true)
+        },
+        ExecuteMsg::DisallowToken { token_address } => {
+            set_token_allowed(deps, info, token_address, false)
+        },
+        ExecuteMsg::Receive(wrapper) => {
+            receive_cw20(deps, env, info, wrapper)
+        },
+        ExecuteMsg::WithdrawReserves { amount, denom } => {
+            withdraw_reserves(deps, info, amount, denom)
+        },
+        ExecuteMsg::SetMinCollateralAmount { amount } => {
+            set_min_collateral_amount(deps, info, amount)
+        },
+        ExecuteMsg::BatchRepay { denom, repayments } => {
+            batch_repay(deps, env, info, denom, repayments)
+        },
+        ExecuteMsg::RecoverStaleCollateral { borrower } => {
+            recover_stale_collateral(deps, env, info, borrower)
+        },
+        ExecuteMsg::CloseDustLoan { borrower, denom } => {
+            close_dust_loan(deps, env, info, borrower, denom)
+        },
+        ExecuteMsg::ProvideLiquidity {} => {
+            provide_liquidity(deps, info)
+        },
+        ExecuteMsg::RemoveLiquidity { amount, denom } => {
+            remove_liquidity(deps, info, amount, denom)
+        },
+        ExecuteMsg::UpdateConfig(update) => {
+            update_config(deps, info, *update)
+        },
+        ExecuteMsg::SwapCollateral { from_token, to_token, from_amount, to_amount } => {
+            swap_collateral(deps, env, info, from_token, to_token, from_amount, to_amount)
+        },
+        ExecuteMsg::SetLiquidationBonus { bonus } => {
+            set_liquidation_bonus(deps, info, bonus)
+        },
+        ExecuteMsg::ExtendLoan { denom } => {
+            extend_loan(deps, env, info, denom)
+        },
+        ExecuteMsg::SelfLiquidate {} => {
+            self_liquidate(deps, env, info)
+        },
+        ExecuteMsg::WriteOffBadDebt { borrower } => {
+            write_off_bad_debt(deps, env, info, borrower)
+        },
+        ExecuteMsg::TransferLoan { new_borrower } => {
+            transfer_loan(deps, info, new_borrower)
+        },
+        ExecuteMsg::AcceptLoanTransfer { from } => {
+            accept_loan_transfer(deps, info, from)
+        },
+        ExecuteMsg::RepayFromReserves { borrower } => {
+            repay_from_reserves(deps, env, info, borrower)
+        },
+        ExecuteMsg::SetTokenRate { token_address, rate } => {
+            set_token_rate(deps, info, token_address, rate)
+        },
+        ExecuteMsg::Refinance {} => {
+            refinance(deps, env, info)
+        },
+        ExecuteMsg::BatchLiquidate { limit } => {
+            batch_liquidate(deps, env, info, limit)
+        },
+        ExecuteMsg::Ping {} => {
+            Ok(Response::new()
+                .add_attribute("action", "ping")
+                .add_attribute("pong", "pong")
+                .add_attribute("block_height", env.block.height.to_string()))
+        },
+    }
+}
+
+// a zero max means no upper bound, otherwise the bounds may not cross
+fn ensure_valid_loan_bounds(min: u64, max: u64) -> Result<(), ContractError> {
+    if max != 0 && min > max {
+        return Err(ContractError::InvalidLoanDuration { min, max });
+    }
+    Ok(())
+}
+
+// reject liquidation bonuses above MAX_LIQUIDATION_BONUS
+fn ensure_valid_liquidation_bonus(bonus: Decimal) -> Result<(), ContractError> {
+    if bonus > MAX_LIQUIDATION_BONUS {
+        return Err(ContractError::InvalidLiquidationBonus { max: MAX_LIQUIDATION_BONUS });
+    }
+    Ok(())
+}
+
+// reject base rates above MAX_INTEREST_RATE (Decimal is unsigned, so there is no lower bound to check)
+fn ensure_valid_interest_rate(rate: Decimal) -> Result<(), ContractError> {
+    if rate > MAX_INTEREST_RATE {
+        return Err(ContractError::InvalidInterestRate { max: MAX_INTEREST_RATE });
+    }
+    Ok(())
+}
+
+// append a base rate change to RATE_HISTORY; a later change in the same block replaces that block's entry,
+// since only the last rate set in a block is ever in effect
+fn record_rate_change(storage: &mut dyn Storage, env: &Env, rate: Decimal) -> Result<(), ContractError> {
+    RATE_HISTORY.save(storage, env.block.time.seconds(), &rate)?;
+    Ok(())
+}
+
+// reject the operation while the contract is paused
+fn ensure_not_paused(deps: Deps) -> Result<(), ContractError> {
+    if CONFIG.load(deps.storage)?.paused {
+        return Err(ContractError::ContractPaused {});
+    }
+    Ok(())
+}
+
+// reject calls made while another guarded operation's transfers are still in flight
+fn ensure_not_locked(deps: Deps) -> Result<(), ContractError> {
+    if REENTRANCY_LOCK.may_load(deps.storage)?.unwrap_or(false) {
+        return Err(ContractError::Reentrancy {});
+    }
+    Ok(())
+}
+
+// take the reentrancy lock and wrap outgoing messages so the last one replies and releases it
+fn lock_until_reply(storage: &mut dyn Storage, mut messages: Vec<CosmosMsg>, reply_id: u64) -> StdResult<Vec<SubMsg>> {
+    let Some(last) = messages.pop() else {
+        return Ok(vec![]);
+    };
+    REENTRANCY_LOCK.save(storage, &true)?;
+    let mut submessages: Vec<SubMsg> = messages.into_iter().map(SubMsg::new).collect();
+    submessages.push(SubMsg::reply_always(last, reply_id));
+    Ok(submessages)
+}
+
+// the attached funds must be exactly `amount` of `denom`
+fn ensure_paid(info: &MessageInfo, denom: &str, amount: Uint128) -> Result<(), ContractError> {
+    let paid = match info.funds.as_slice() {
+        [fund] if fund.denom == denom => fund.amount,
+        _ => Uint128::zero(),
+    };
+    if paid != amount {
+        return Err(ContractError::FundsMismatch { denom: denom.to_string(), amount });
     }
+    Ok(())
 }
 
 // deposit collateral logic
-fn deposit_collateral(deps: DepsMut, info: MessageInfo, token_address: String, amount: Uint128) -> StdResult<Response> {
+fn deposit_collateral(deps: DepsMut, env: Env, info: MessageInfo, token_address: String, amount: Uint128) -> Result<Response, ContractError> {
+    // 必須實際附上與 token_address 相同 denom、且數量等於 amount 的資金
+    if !amount.is_zero() {
+        ensure_paid(&info, &token_address, amount)?;
+    }
+    store_collateral(deps, &env, &info.sender, token_address, amount, false)
+}
+
+// CW20 receive hook: the sending token contract becomes the collateral token
+fn receive_cw20(deps: DepsMut, env: Env, info: MessageInfo, wrapper: Cw20ReceiveMsg) -> Result<Response, ContractError> {
+    let owner = deps.api.addr_validate(&wrapper.sender)?;
+    match from_json(&wrapper.msg)? {
+        ReceiveMsg::DepositCollateral {} => {
+            store_collateral(deps, &env, &owner, info.sender.to_string(), wrapper.amount, true)
+        },
+    }
+}
+
+// record collateral received from `owner`, shared by native and CW20 deposits
+fn store_collateral(deps: DepsMut, env: &Env, owner: &Addr, token_address: String, amount: Uint128, cw20: bool) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
     if amount.is_zero() {
-        return Err(StdError::generic_err("Amount cannot be zero"));
+        return Err(ContractError::ZeroAmount {});
+    }
+    let config = CONFIG.load(deps.storage)?;
+    if amount < config.min_collateral_amount {
+        return Err(ContractError::DepositBelowMinimum { min: config.min_collateral_amount });
+    }
+    // 只接受白名單內的代幣作為抵押品
+    if !ALLOWED_TOKENS.may_load(deps.storage, token_address.clone())?.unwrap_or(false) {
+        return Err(ContractError::TokenNotAllowed { token_address });
     }
-    let collateral = Collateral { token_address, amount };
-    COLLATERALS.save(deps.storage, info.sender.to_string(), &collateral)?;
+    // 同一代幣重複存入時累加數量並更新存入時間，不同代幣則各自保存
+    let now = env.block.time.seconds();
+    let collateral = match COLLATERALS.may_load(deps.storage, (owner, token_address.as_str()))? {
+        Some(mut collateral) => {
+            collateral.amount = collateral.amount.checked_add(amount)?;
+            collateral.deposited_at = now;
+            collateral
+        }
+        None => Collateral { token_address: token_address.clone(), amount, cw20, deposited_at: now },
+    };
+    save_collateral(deps.storage, (owner, token_address.as_str()), &collateral)?;
+    add_to_total(deps.storage, &TOTAL_COLLATERAL, amount)?;
+
+    // 回報存入後的健康係數，讓借款人立即看到安全邊際的變化
+    let debt = outstanding_debt(deps.as_ref(), &config, owner, now, env.block.height)?;
+    let health_factor = health_factor(deps.as_ref(), &config, owner, debt, now)?;
     Ok(Response::new()
         .add_attribute("action", "deposit_collateral")
-        .add_attribute("amount", amount.to_string()))
+        .add_attribute("owner", owner)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("new_total", collateral.amount.to_string())
+        .add_attribute("health_factor", health_factor.to_string()))
+}
+
+// message returning `amount` of a collateral token to `recipient`
+fn collateral_transfer_msg(recipient: &Addr, collateral: &Collateral, amount: Uint128) -> StdResult<CosmosMsg> {
+    if collateral.cw20 {
+        Ok(WasmMsg::Execute {
+            contract_addr: collateral.token_address.clone(),
+            msg: to_json_binary(&Cw20ExecuteMsg::Transfer { recipient: recipient.to_string(), amount })?,
+            funds: vec![],
+        }
+        .into())
+    } else {
+        Ok(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![coin(amount.u128(), &collateral.token_address)],
+        }
+        .into())
+    }
+}
+
+// percentage withdrawal logic: withdraws `pct` of the sender's `token_address` collateral, rounded down
+fn withdraw_collateral_pct(deps: DepsMut, env: Env, info: MessageInfo, token_address: String, pct: Decimal) -> Result<Response, ContractError> {
+    if pct > Decimal::one() {
+        return Err(ContractError::InvalidPercentage {});
+    }
+    let collateral = COLLATERALS
+        .may_load(deps.storage, (&info.sender, token_address.as_str()))?
+        .ok_or(ContractError::NoCollateral {})?;
+    let amount = mul_decimal(collateral.amount, pct)?;
+    Ok(withdraw_collateral(deps, env, info, token_address, amount)?.add_attribute("pct", pct.to_string()))
 }
 
 // withdraw collateral logic
-fn withdraw_collateral(deps: DepsMut, info: MessageInfo, token_address: String, amount: Uint128) -> StdResult<Response> {
+fn withdraw_collateral(mut deps: DepsMut, env: Env, info: MessageInfo, token_address: String, amount: Uint128) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    ensure_not_locked(deps.as_ref())?;
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
     // 首先檢查用戶是否有足夠的抵押品可供取出
-    let collateral = COLLATERALS.load(deps.storage, info.sender.to_string())?;
-    if collateral.token_address != token_address || collateral.amount < amount {
-        return Err(StdError::generic_err("Insufficient collateral or mismatched token address"));
+    let key = (&info.sender, token_address.as_str());
+    let collateral = COLLATERALS
+        .may_load(deps.storage, key)?
+        .ok_or(ContractError::NoCollateral {})?;
+    if collateral.amount < amount {
+        return Err(ContractError::InsufficientCollateral {});
+    }
+
+    let remaining = collateral.amount.checked_sub(amount)?;
+
+    // 有未還清借款時，剩餘抵押品折算價值 * max_ltv 必須足以支撐所有欠款（含利息）
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps.as_ref(), &config, &info.sender, now, env.block.height)?;
+    if !debt.is_zero() {
+        refresh_prices(deps.branch(), &config, &info.sender, now)?;
+        let value = collateral_value(deps.as_ref(), &config, &info.sender, now)?
+            .checked_sub(weighted_value(deps.as_ref(), &config, &token_address, collateral.amount, now)?)?
+            .checked_add(weighted_value(deps.as_ref(), &config, &token_address, remaining, now)?)?;
+        let max_borrow = mul_decimal(value, config.max_ltv)?;
+        if max_borrow < debt {
+            return Err(ContractError::CollateralLocked { remaining: max_borrow, borrowed: debt });
+        }
     }
 
     // 更新抵押品的狀態
     if collateral.amount == amount {
         // 如果取出的數量等於總抵押量，則從存儲中移除該抵押品記錄
-        COLLATERALS.remove(deps.storage, info.sender.to_string());
+        remove_collateral(deps.storage, key)?;
     } else {
         // 否則更新存儲的抵押品數量
         let updated_collateral = Collateral {
-            token_address: collateral.token_address,
-            amount: collateral.amount - amount,
+            amount: remaining,
+            ..collateral.clone()
         };
-        COLLATERALS.save(deps.storage, info.sender.to_string(), &updated_collateral)?;
+        save_collateral(deps.storage, key, &updated_collateral)?;
     }
+    sub_from_total(deps.storage, &TOTAL_COLLATERAL, amount)?;
+
+    // 將抵押品轉回給用戶（原生代幣用 BankMsg，CW20 用 Transfer）
+    let transfer_msg = collateral_transfer_msg(&info.sender, &collateral, amount)?;
+    let pending = PendingTransfer {
+        owner: info.sender.clone(),
+        token_address: token_address.clone(),
+        amount,
+        fee: Uint128::zero(),
+        cw20: collateral.cw20,
+        request_id: None,
+        deposited_at: collateral.deposited_at,
+    };
+    PENDING_TRANSFER.save(deps.storage, &pending)?;
 
-    // 模擬將抵押品返回給用戶的過程（在實際合約中，這可能涉及調用其他合約或處理特定的資產轉移邏輯）
-    // 這裡僅示範將操作結果作為響應屬性返回
     Ok(Response::new()
+        .add_submessages(lock_until_reply(deps.storage, vec![transfer_msg], WITHDRAW_REPLY_ID)?)
         .add_attribute("action", "withdraw_collateral")
         .add_attribute("amount", amount.to_string())
         .add_attribute("token_address", token_address))
 }
 
-// borrow logic
-fn borrow(deps: DepsMut, env: Env, info: MessageInfo, amount: Uint128) -> StdResult<Response> {
-    let loan_info = LoanInfo {
-        amount_borrowed: amount,
-        interest_rate: Decimal::percent(5), // Assumes a fixed annual interest rate of 5%
-        loan_start_time: env.block.time.seconds(),
+// collateral swap logic: the attached `to_amount` of `to_token` replaces `from_amount` of `from_token`,
+// which is returned to the sender, as long as the new collateral still supports the outstanding debt
+fn swap_collateral(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from_token: String,
+    to_token: String,
+    from_amount: Uint128,
+    to_amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    ensure_not_locked(deps.as_ref())?;
+    if from_amount.is_zero() || to_amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    ensure_paid(&info, &to_token, to_amount)?;
+    if !ALLOWED_TOKENS.may_load(deps.storage, to_token.clone())?.unwrap_or(false) {
+        return Err(ContractError::TokenNotAllowed { token_address: to_token });
+    }
+    let from_key = (&info.sender, from_token.as_str());
+    let mut from = COLLATERALS
+        .may_load(deps.storage, from_key)?
+        .ok_or(ContractError::InsufficientCollateral {})?;
+    if from.amount < from_amount {
+        return Err(ContractError::InsufficientCollateral {});
+    }
+    let remaining = from.amount.checked_sub(from_amount)?;
+    // 換入的代幣與換出的相同時，換入前的部位即為換出後剩餘的數量
+    let to_before = if to_token == from_token {
+        remaining
+    } else {
+        COLLATERALS.may_load(deps.storage, (&info.sender, to_token.as_str()))?.map(|c| c.amount).unwrap_or_default()
     };
-    LOANS.save(deps.storage, info.sender.to_string(), &loan_info)?;
+    let to_after = to_before.checked_add(to_amount)?;
+    // 換入後的部位與存入相同，不得低於最低抵押數量
+    let config = CONFIG.load(deps.storage)?;
+    if to_after < config.min_collateral_amount {
+        return Err(ContractError::DepositBelowMinimum { min: config.min_collateral_amount });
+    }
 
-    let payout = coin(amount.u128(), "usdc"); // Example assumes "usdc" as the currency
-    let bank_msg = BankMsg::Send {
-        to_address: info.sender.into(),
-        amount: vec![payout],
+    // 有未還清借款時，與取出相同：換倉後的抵押品折算價值 * max_ltv 必須足以支撐所有欠款（含利息）
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps.as_ref(), &config, &info.sender, now, env.block.height)?;
+    if !debt.is_zero() {
+        refresh_prices(deps.branch(), &config, &info.sender, now)?;
+        let value = collateral_value(deps.as_ref(), &config, &info.sender, now)?
+            .checked_sub(weighted_value(deps.as_ref(), &config, &from_token, from.amount, now)?)?
+            .checked_add(weighted_value(deps.as_ref(), &config, &from_token, remaining, now)?)?
+            .checked_sub(weighted_value(deps.as_ref(), &config, &to_token, to_before, now)?)?
+            .checked_add(weighted_value(deps.as_ref(), &config, &to_token, to_after, now)?)?;
+        let max_borrow = mul_decimal(value, config.max_ltv)?;
+        if max_borrow < debt {
+            return Err(ContractError::CollateralLocked { remaining: max_borrow, borrowed: debt });
+        }
+    }
+    let transfer_msg = collateral_transfer_msg(&info.sender, &from, from_amount)?;
+
+    from.amount = remaining;
+    if from.amount.is_zero() {
+        remove_collateral(deps.storage, from_key)?;
+    } else {
+        save_collateral(deps.storage, from_key, &from)?;
+    }
+    let collateral = match COLLATERALS.may_load(deps.storage, (&info.sender, to_token.as_str()))? {
+        Some(mut collateral) => {
+            collateral.amount = collateral.amount.checked_add(to_amount)?;
+            collateral.deposited_at = env.block.time.seconds();
+            collateral
+        }
+        None => Collateral { token_address: to_token.clone(), amount: to_amount, cw20: false, deposited_at: env.block.time.seconds() },
     };
+    save_collateral(deps.storage, (&info.sender, to_token.as_str()), &collateral)?;
+    add_to_total(deps.storage, &TOTAL_COLLATERAL, to_amount)?;
+    sub_from_total(deps.storage, &TOTAL_COLLATERAL, from_amount)?;
 
     Ok(Response::new()
-        .add_message(bank_msg)
-        .add_attribute("action", "borrow")
-        .add_attribute("amount", amount.to_string()))
+        .add_submessages(lock_until_reply(deps.storage, vec![transfer_msg], RELEASE_LOCK_REPLY_ID)?)
+        .add_attribute("action", "swap_collateral")
+        .add_attribute("from_token", from_token)
+        .add_attribute("from_amount", from_amount.to_string())
+        .add_attribute("to_token", to_token)
+        .add_attribute("to_amount", to_amount.to_string()))
 }
 
-// repay logic
-fn repay_loan(deps: DepsMut, info: MessageInfo, amount: Uint128) -> StdResult<Response> {
-    let loan = LOANS.load(deps.storage, info.sender.to_string())?;
-    let interest = loan.amount_borrowed * loan.interest_rate;
-    let total_due = loan.amount_borrowed + interest;
+// sum of all collateral positions held by `owner`, valued 1:1
+fn total_collateral(deps: Deps, owner: &Addr) -> Result<Uint128, ContractError> {
+    COLLATERALS
+        .prefix(owner)
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| Ok(total.checked_add(item?.1.amount)?))
+}
 
-    if amount < total_due {
-        return Err(StdError::generic_err("Repayment amount is not enough to cover the loan and interest"));
-    }
-    LOANS.remove(deps.storage, info.sender.to_string());
+// decimals of the borrow denom and of `token_address`; tokens without decimals share the borrow denom's
+fn token_decimals(deps: Deps, config: &Config, token_address: &str) -> StdResult<(u8, u8)> {
+    let borrow_decimals = TOKEN_DECIMALS.may_load(deps.storage, config.borrow_denom.clone())?.unwrap_or(DEFAULT_DECIMALS);
+    let decimals = TOKEN_DECIMALS.may_load(deps.storage, token_address.to_string())?.unwrap_or(borrow_decimals);
+    Ok((borrow_decimals, decimals))
+}
 
-    Ok(Response::new()
-        .add_attribute("action", "repay_loan")
-        .add_attribute("amount", amount.to_string())
-        .add_attribute("interest_paid", interest.to_string()))
+// market value of `amount` of `token_address` in borrow_denom units: rescaled to the borrow denom's decimals
+// and priced at token_price
+fn token_value(deps: Deps, config: &Config, token_address: &str, amount: Uint128, now: u64) -> Result<Uint128, ContractError> {
+    let (borrow_decimals, decimals) = token_decimals(deps, config, token_address)?;
+    let amount = normalize_amount(amount, decimals, borrow_decimals)?;
+    Ok(mul_decimal(amount, token_price(deps, config, token_address, now)?)?)
 }
 
-// Implements interest rate update logic (owner only)
-fn update_interest_rate(deps: DepsMut, info: MessageInfo, new_rate: Decimal) -> StdResult<Response> {
-    // Verify if the sender is the owner
-    let config = CONFIG.load(deps.storage)?;
-    if info.sender != config.owner {
-        return Err(StdError::generic_err("You have no permissions."));
-    }
+// what `amount` of `token_address` counts for as collateral: its token_value weighted by the token's
+// collateral factor, tokens without a factor count as zero. Every check of a position's backing
+// (borrowing, withdrawals, health, liquidation, solvency) values collateral through here
+fn weighted_value(deps: Deps, config: &Config, token_address: &str, amount: Uint128, now: u64) -> Result<Uint128, ContractError> {
+    let factor = COLLATERAL_FACTORS.may_load(deps.storage, token_address.to_string())?.unwrap_or_default();
+    Ok(mul_decimal(token_value(deps, config, token_address, amount, now)?, factor)?)
+}
 
-    // Update the interest rate
-    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
-        conf.base_interest_rate = new_rate;
-        Ok(conf)
-    })?;
+// collateral value of all of `owner`'s positions in borrow_denom units, see weighted_value
+fn collateral_value(deps: Deps, config: &Config, owner: &Addr, now: u64) -> Result<Uint128, ContractError> {
+    COLLATERALS
+        .prefix(owner)
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| {
+            let (token_address, collateral) = item?;
+            Ok(total.checked_add(weighted_value(deps, config, &token_address, collateral.amount, now)?)?)
+        })
+}
 
-    Ok(Response::new()
-        .add_attribute("action", "update_interest_rate")
-        .add_attribute("new_rate", new_rate.to_string()))
+// amount of `token_address` worth `value` in borrow_denom units, the inverse of token_value (rounded down)
+fn token_amount(deps: Deps, config: &Config, token_address: &str, value: Uint128, now: u64) -> Result<Uint128, ContractError> {
+    let (borrow_decimals, decimals) = token_decimals(deps, config, token_address)?;
+    let amount = value.checked_div_floor(token_price(deps, config, token_address, now)?)?;
+    Ok(normalize_amount(amount, borrow_decimals, decimals)?)
 }
 
+// smallest amount of `token_address` whose weighted_value reaches `value`, the inverse of weighted_value
+// (rounded up); None when the token has no collateral factor or price, so no amount of it ever does
+fn weighted_amount(deps: Deps, config: &Config, token_address: &str, value: Uint128, now: u64) -> Result<Option<Uint128>, ContractError> {
+    let factor = COLLATERAL_FACTORS.may_load(deps.storage, token_address.to_string())?.unwrap_or_default();
+    let price = token_price(deps, config, token_address, now)?;
+    if factor.is_zero() || price.is_zero() {
+        return Ok(None);
+    }
+    let (borrow_decimals, decimals) = token_decimals(deps, config, token_address)?;
+    let normalized = value.checked_div_ceil(factor)?.checked_div_ceil(price)?;
+    let mut amount = normalize_amount(normalized, borrow_decimals, decimals)?;
+    if normalize_amount(amount, decimals, borrow_decimals)? < normalized {
+        amount = amount.checked_add(Uint128::one())?;
+    }
+    Ok(Some(amount))
+}
 
-//Possible Issues:
+// move collateral worth `value` in borrow_denom units (at token_value) from `owner` to `recipient`, one
+// position at a time, converting what is left to take into units of the last token touched; returns the
+// market value actually taken, which falls short of `value` once the positions run out
+fn seize_collateral(deps: DepsMut, config: &Config, owner: &Addr, recipient: &Addr, value: Uint128, now: u64) -> Result<(Uint128, Vec<CosmosMsg>), ContractError> {
+    let positions = COLLATERALS
+        .prefix(owner)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut to_seize = value;
+    let mut seized = Uint128::zero();
+    let mut messages = vec![];
+    for (token_address, mut collateral) in positions {
+        if to_seize.is_zero() {
+            break;
+        }
+        // 整個部位價值不足時全數取走，否則依價格與小數位數換算成該代幣的數量
+        let position_value = token_value(deps.as_ref(), config, &token_address, collateral.amount, now)?;
+        let (take, taken_value) = if position_value <= to_seize {
+            (collateral.amount, position_value)
+        } else {
+            let take = token_amount(deps.as_ref(), config, &token_address, to_seize, now)?.min(collateral.amount);
+            (take, to_seize)
+        };
+        to_seize = to_seize.checked_sub(taken_value)?;
+        seized = seized.checked_add(taken_value)?;
+        if take.is_zero() {
+            continue;
+        }
+        messages.push(collateral_transfer_msg(recipient, &collateral, take)?);
+        sub_from_total(deps.storage, &TOTAL_COLLATERAL, take)?;
+        collateral.amount = collateral.amount.checked_sub(take)?;
+        let key = (owner, token_address.as_str());
+        if collateral.amount.is_zero() {
+            remove_collateral(deps.storage, key)?;
+        } else {
+            save_collateral(deps.storage, key, &collateral)?;
+        }
+    }
+    Ok((seized, messages))
+}
+
+// cached price of `token_address` if it is no older than price_max_age_seconds
+fn fresh_cached_price(deps: Deps, config: &Config, token_address: &str, now: u64) -> StdResult<Option<Decimal>> {
+    let cached = PRICE_CACHE.may_load(deps.storage, token_address.to_string())?;
+    Ok(cached
+        .filter(|cached| now.saturating_sub(cached.updated_at) <= config.price_max_age_seconds)
+        .map(|cached| cached.price))
+}
+
+// value of one unit of `token_address` in borrow_denom: 1:1 without an oracle, otherwise the cached
+// price while fresh, falling back to querying the oracle directly
+fn token_price(deps: Deps, config: &Config, token_address: &str, now: u64) -> Result<Decimal, ContractError> {
+    let Some(oracle) = &config.oracle else {
+        return Ok(Decimal::one());
+    };
+    // 價格以 borrow_denom 報價，borrow_denom 本身恆為 1
+    if token_address == config.borrow_denom {
+        return Ok(Decimal::one());
+    }
+    if let Some(price) = fresh_cached_price(deps, config, token_address, now)? {
+        return Ok(price);
+    }
+    let msg = OracleQueryMsg::Price { denom: token_address.to_string() };
+    let res: OraclePriceResponse = deps.querier.query_wasm_smart(oracle, &msg)?;
+    Ok(res.price)
+}
+
+// re-fetch stale prices of `owner`'s collateral tokens into PRICE_CACHE, so valuations later in the
+// same message (and later messages within price_max_age_seconds) skip the oracle query
+fn refresh_prices(deps: DepsMut, config: &Config, owner: &Addr, now: u64) -> Result<(), ContractError> {
+    if config.oracle.is_none() {
+        return Ok(());
+    }
+    let tokens = COLLATERALS
+        .prefix(owner)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for token_address in tokens {
+        if fresh_cached_price(deps.as_ref(), config, &token_address, now)?.is_some() {
+            continue;
+        }
+        let price = token_price(deps.as_ref(), config, &token_address, now)?;
+        PRICE_CACHE.save(deps.storage, token_address, &CachedPrice { price, updated_at: now })?;
+    }
+    Ok(())
+}
+
+// principal of all of `borrower`'s loans, each valued in borrow_denom at token_value
+fn total_principal(deps: Deps, config: &Config, borrower: &Addr, now: u64) -> Result<Uint128, ContractError> {
+    LOANS
+        .prefix(borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| {
+            let (denom, loan) = item?;
+            Ok(total.checked_add(token_value(deps, config, &denom, loan.amount_borrowed, now)?)?)
+        })
+}
+
+// amount due on all of `borrower`'s loans including interest, each valued in borrow_denom at token_value
+fn outstanding_debt(deps: Deps, config: &Config, borrower: &Addr, now: u64, height: u64) -> Result<Uint128, ContractError> {
+    LOANS
+        .prefix(borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| {
+            let (denom, loan) = item?;
+            Ok(total.checked_add(token_value(deps, config, &denom, total_due(&loan, config, now, height)?, now)?)?)
+        })
+}
+
+// (collateral value * liquidation threshold) / outstanding debt including interest
+fn health_factor(deps: Deps, config: &Config, borrower: &Addr, debt: Uint128, now: u64) -> Result<Decimal, ContractError> {
+    if debt.is_zero() {
+        return Ok(Decimal::MAX);
+    }
+    let backing = mul_decimal(collateral_value(deps, config, borrower, now)?, config.liquidation_threshold)?;
+    Ok(Decimal::checked_from_ratio(backing, debt)?)
+}
+
+// increase a running protocol total
+fn add_to_total(storage: &mut dyn Storage, total: &Item<Uint128>, amount: Uint128) -> Result<(), ContractError> {
+    let current = total.may_load(storage)?.unwrap_or_default();
+    total.save(storage, &current.checked_add(amount)?)?;
+    Ok(())
+}
+
+// write off `amount` of debt (valued in borrow_denom) nobody will repay: absorbed by the borrow_denom reserves
+// as far as they go and recorded in TOTAL_BAD_DEBT; returns the reserves used
+fn write_off_debt(storage: &mut dyn Storage, config: &Config, amount: Uint128) -> Result<Uint128, ContractError> {
+    let reserves_used = amount.min(PROTOCOL_RESERVES.may_load(storage, &config.borrow_denom)?.unwrap_or_default());
+    sub_from_denom_total(storage, &PROTOCOL_RESERVES, &config.borrow_denom, reserves_used)?;
+    add_to_total(storage, &TOTAL_BAD_DEBT, amount)?;
+    Ok(reserves_used)
+}
+
+// increase the `denom` entry of a running protocol total kept per denom
+fn add_to_denom_total(storage: &mut dyn Storage, total: &Map<&str, Uint128>, denom: &str, amount: Uint128) -> Result<(), ContractError> {
+    let current = total.may_load(storage, denom)?.unwrap_or_default();
+    total.save(storage, denom, &current.checked_add(amount)?)?;
+    Ok(())
+}
+
+// decrease the `denom` entry of a running protocol total kept per denom, erroring rather than going below zero
+fn sub_from_denom_total(storage: &mut dyn Storage, total: &Map<&str, Uint128>, denom: &str, amount: Uint128) -> Result<(), ContractError> {
+    let current = total.may_load(storage, denom)?.unwrap_or_default();
+    total.save(storage, denom, &current.checked_sub(amount)?)?;
+    Ok(())
+}
+
+// decrease a running protocol total, erroring rather than going below zero
+fn sub_from_total(storage: &mut dyn Storage, total: &Item<Uint128>, amount: Uint128) -> Result<(), ContractError> {
+    let current = total.may_load(storage)?.unwrap_or_default();
+    total.save(storage, &current.checked_sub(amount)?)?;
+    Ok(())
+}
+
+// save a loan, counting it if the key is new
+fn save_loan(storage: &mut dyn Storage, key: (&Addr, &str), loan: &LoanInfo) -> StdResult<()> {
+    if !LOANS.has(storage, key) {
+        let count = LOAN_COUNT.may_load(storage)?.unwrap_or_default() + 1;
+        LOAN_COUNT.save(storage, &count)?;
+    }
+    LOANS.save(storage, key, loan)
+}
+
+// remove a loan, uncounting it if it existed
+fn remove_loan(storage: &mut dyn Storage, key: (&Addr, &str)) -> StdResult<()> {
+    if LOANS.has(storage, key) {
+        let count = LOAN_COUNT.may_load(storage)?.unwrap_or_default().saturating_sub(1);
+        LOAN_COUNT.save(storage, &count)?;
+        LOANS.remove(storage, key);
+    }
+    Ok(())
+}
+
+// save a collateral position, counting it if the key is new and moving its token's total by the change
+fn save_collateral(storage: &mut dyn Storage, key: (&Addr, &str), collateral: &Collateral) -> StdResult<()> {
+    let previous = COLLATERALS.may_load(storage, key)?;
+    if previous.is_none() {
+        let count = COLLATERAL_COUNT.may_load(storage)?.unwrap_or_default() + 1;
+        COLLATERAL_COUNT.save(storage, &count)?;
+    }
+    let total = COLLATERAL_TOTALS
+        .may_load(storage, key.1.to_string())?
+        .unwrap_or_default()
+        .checked_sub(previous.map(|previous| previous.amount).unwrap_or_default())?
+        .checked_add(collateral.amount)?;
+    save_token_total(storage, key.1, total)?;
+    COLLATERALS.save(storage, key, collateral)
+}
+
+// remove a collateral position, uncounting it and its amount if it existed
+fn remove_collateral(storage: &mut dyn Storage, key: (&Addr, &str)) -> StdResult<()> {
+    if let Some(collateral) = COLLATERALS.may_load(storage, key)? {
+        let count = COLLATERAL_COUNT.may_load(storage)?.unwrap_or_default().saturating_sub(1);
+        COLLATERAL_COUNT.save(storage, &count)?;
+        let total = COLLATERAL_TOTALS.may_load(storage, key.1.to_string())?.unwrap_or_default();
+        save_token_total(storage, key.1, total.checked_sub(collateral.amount)?)?;
+        COLLATERALS.remove(storage, key);
+    }
+    Ok(())
+}
+
+// store a token's collateral total, dropping the entry once nothing of it is held
+fn save_token_total(storage: &mut dyn Storage, token_address: &str, total: Uint128) -> StdResult<()> {
+    if total.is_zero() {
+        COLLATERAL_TOTALS.remove(storage, token_address.to_string());
+        Ok(())
+    } else {
+        COLLATERAL_TOTALS.save(storage, token_address.to_string(), &total)
+    }
+}
+
+// borrow logic
+#[allow(clippy::too_many_arguments)]
+fn borrow(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    denom: String,
+    amount: Uint128,
+    rate_mode: RateMode,
+    duration: Option<u64>,
+    request_id: Option<String>,
+) -> Result<Response, ContractError> {
+    // 客戶端重送已處理過的請求時直接回傳成功，不會開立第二筆借款
+    if let Some(request_id) = &request_id {
+        if PROCESSED_BORROWS.has(deps.storage, (&info.sender, request_id.as_str())) {
+            return Ok(Response::new()
+                .add_attribute("action", "borrow")
+                .add_attribute("request_id", request_id)
+                .add_attribute("duplicate", "true"));
+        }
+        PROCESSED_BORROWS.save(deps.storage, (&info.sender, request_id.as_str()), &())?;
+    }
+    ensure_not_paused(deps.as_ref())?;
+    ensure_not_locked(deps.as_ref())?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    refresh_prices(deps.branch(), &config, &info.sender, now)?;
+    check_borrow(deps.as_ref(), &config, &info.sender, &denom, amount, now)?;
+    let term_seconds = duration.unwrap_or(config.max_loan_seconds);
+    if term_seconds < config.min_loan_seconds || (config.max_loan_seconds != 0 && term_seconds > config.max_loan_seconds) {
+        return Err(ContractError::InvalidLoanDuration { min: config.min_loan_seconds, max: config.max_loan_seconds });
+    }
+
+    // 手續費從撥款中扣除，債務仍以全額計；撥款成功後手續費轉入金庫
+    let fee = mul_decimal(amount, config.origination_fee)?;
+    let net_payout = amount.checked_sub(fee)?;
+    // 合約實際持有的餘額必須足以撥款並支付轉入金庫的手續費（合計為借款全額），避免在 bank 模組中才失敗；
+    // 以同幣別存入的抵押品屬於借款人，不可借出
+    let balance = deps.querier.query_balance(&env.contract.address, &denom)?.amount;
+    let held_as_collateral = COLLATERAL_TOTALS.may_load(deps.storage, denom.clone())?.unwrap_or_default();
+    let available = balance.saturating_sub(held_as_collateral);
+    if available < amount {
+        return Err(ContractError::InsufficientLiquidity { available });
+    }
+
+    let loan_info = LoanInfo {
+        amount_borrowed: amount,
+        interest_rate: borrower_rate(deps.as_ref(), &config, &info.sender, &denom)?, // 開立時的利率，之後不隨利用率變動
+        loan_start_time: now,
+        loan_start_height: env.block.height,
+        rate_mode,
+        interest_free_until: now.saturating_add(config.grace_interest_free_seconds),
+        term_seconds,
+        term_start_time: now,
+    };
+    save_loan(deps.storage, (&info.sender, denom.as_str()), &loan_info)?;
+    LAST_BORROW.save(deps.storage, &info.sender, &now)?;
+    add_to_denom_total(deps.storage, &TOTAL_BORROWED, &denom, amount)?;
+
+    let payout = make_payout(net_payout, &denom)?;
+    let bank_msg = BankMsg::Send {
+        to_address: info.sender.to_string(),
+        amount: vec![payout],
+    };
+    let pending = PendingTransfer {
+        owner: info.sender.clone(),
+        token_address: denom.clone(),
+        amount,
+        fee,
+        cw20: false,
+        request_id: request_id.clone(),
+        deposited_at: 0,
+    };
+    PENDING_TRANSFER.save(deps.storage, &pending)?;
+
+    // 借款後的槓桿：所有幣別欠款 / 抵押品折算價值
+    let value = collateral_value(deps.as_ref(), &config, &info.sender, now)?;
+    let debt = outstanding_debt(deps.as_ref(), &config, &info.sender, now, env.block.height)?;
+    let ltv = if value.is_zero() { Decimal::zero() } else { Decimal::checked_from_ratio(debt, value)? };
+    let capacity = borrow_capacity(deps.as_ref(), &config, &info.sender, now, env.block.height)?;
+    let event = Event::new("loan_opened")
+        .add_attribute("borrower", &info.sender)
+        .add_attribute("denom", &denom)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("interest_rate", loan_info.interest_rate.to_string())
+        .add_attribute("fee", fee.to_string())
+        .add_attribute("timestamp", loan_info.loan_start_time.to_string());
+
+    Ok(Response::new()
+        .add_submessages(lock_until_reply(deps.storage, vec![bank_msg.into()], BORROW_REPLY_ID)?)
+        .add_event(event)
+        .add_attribute("action", "borrow")
+        .add_attribute("denom", denom)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("fee", fee.to_string())
+        .add_attribute("ltv", ltv.to_string())
+        .add_attribute("remaining_borrow_capacity", capacity.to_string()))
+}
+
+// how much more `borrower` may borrow: collateral value * max_ltv - outstanding debt, floored at zero
+fn borrow_capacity(deps: Deps, config: &Config, borrower: &Addr, now: u64, height: u64) -> Result<Uint128, ContractError> {
+    let max_borrow = mul_decimal(collateral_value(deps, config, borrower, now)?, config.max_ltv)?;
+    Ok(max_borrow.saturating_sub(outstanding_debt(deps, config, borrower, now, height)?))
+}
+
+// checks a new loan of `amount` to `borrower` must pass
+fn check_borrow(deps: Deps, config: &Config, borrower: &Addr, denom: &str, amount: Uint128, now: u64) -> Result<(), ContractError> {
+    if denom.trim().is_empty() {
+        return Err(ContractError::InvalidDenom {});
+    }
+    // 冷卻期間內不可再次借款，即使上一筆已還清
+    if config.borrow_cooldown_seconds != 0 {
+        if let Some(last) = LAST_BORROW.may_load(deps.storage, borrower)? {
+            let ready_at = last.saturating_add(config.borrow_cooldown_seconds);
+            if now < ready_at {
+                return Err(ContractError::BorrowCooldown { ready_at });
+            }
+        }
+    }
+    // 每位借款人在每個幣別同時只能有一筆借款，避免覆蓋既有借款及其應計利息
+    if LOANS.has(deps.storage, (borrower, denom)) {
+        return Err(ContractError::LoanAlreadyExists {});
+    }
+
+    // 完全沒有抵押品時直接回報，而不是 max_borrow 為 0 的 LTV 錯誤
+    if COLLATERALS.prefix(borrower).keys(deps.storage, None, None, Order::Ascending).next().is_none() {
+        return Err(ContractError::NoCollateral {});
+    }
+
+    // 所有幣別的借款本金依價格換算成 borrow_denom 後加總，不得超過抵押品折算後價值 * max_ltv
+    let borrowed = total_principal(deps, config, borrower, now)?.checked_add(token_value(deps, config, denom, amount, now)?)?;
+    let max_borrow = mul_decimal(collateral_value(deps, config, borrower, now)?, config.max_ltv)?;
+    if borrowed > max_borrow {
+        return Err(ContractError::LtvExceeded { max_borrow });
+    }
+
+    // 單一借款人上限，避免單一大戶借光流動性
+    if !config.max_borrow_per_user.is_zero() && borrowed > config.max_borrow_per_user {
+        return Err(ContractError::BorrowLimitExceeded { limit: config.max_borrow_per_user });
+    }
+    Ok(())
+}
+
+// share of the `denom` pool's liquidity currently lent out, capped at 100%;
+// zero while no liquidity has been recorded for it
+fn utilization(deps: Deps, denom: &str) -> Result<Decimal, ContractError> {
+    let liquidity = TOTAL_LIQUIDITY.may_load(deps.storage, denom)?.unwrap_or_default();
+    if liquidity.is_zero() {
+        return Ok(Decimal::zero());
+    }
+    let borrowed = TOTAL_BORROWED.may_load(deps.storage, denom)?.unwrap_or_default();
+    Ok(Decimal::checked_from_ratio(borrowed.min(liquidity), liquidity)?)
+}
+
+// annual rate for a loan in `denom` opened now: base_interest_rate + slope * utilization of that denom
+fn current_borrow_rate(deps: Deps, config: &Config, denom: &str) -> Result<Decimal, ContractError> {
+    let variable = config.slope.checked_mul(utilization(deps, denom)?)?;
+    Ok(config.base_interest_rate.checked_add(variable)?)
+}
+
+// current_borrow_rate for `borrower`, with base_interest_rate replaced by the highest TOKEN_RATES
+// entry among their collateral tokens (tokens without an override count at base_interest_rate)
+fn borrower_rate(deps: Deps, config: &Config, borrower: &Addr, denom: &str) -> Result<Decimal, ContractError> {
+    let tokens = COLLATERALS
+        .prefix(borrower)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut base = None;
+    for token_address in tokens {
+        let rate = TOKEN_RATES.may_load(deps.storage, token_address)?.unwrap_or(config.base_interest_rate);
+        base = base.max(Some(rate));
+    }
+    let variable = config.slope.checked_mul(utilization(deps, denom)?)?;
+    Ok(base.unwrap_or(config.base_interest_rate).checked_add(variable)?)
+}
+
+// repay logic
+fn repay_loan(deps: DepsMut, env: Env, info: MessageInfo, denom: String, amount: Uint128) -> Result<Response, ContractError> {
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    let now = env.block.time.seconds();
+    let config = CONFIG.load(deps.storage)?;
+    // 還款必須實際附上等額的借款幣別
+    ensure_paid(&info, &denom, amount)?;
+    let repayment = apply_repayment(deps.storage, &config, &info.sender, &denom, amount, now, env.block.height)?;
+
+    Ok(Response::new()
+        .add_messages(refund_msg(&info.sender, &denom, repayment.refund)?)
+        .add_event(repaid_event(&info.sender, &denom, amount, &repayment, now))
+        .add_attribute("action", "repay_loan")
+        .add_attribute("denom", denom)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("principal_paid", repayment.principal_paid.to_string())
+        .add_attribute("interest_paid", repayment.interest_paid.to_string())
+        .add_attribute("reserve_cut", repayment.reserve.to_string())
+        .add_attribute("remaining_balance", repayment.remaining_balance.to_string()))
+}
+
+// batch repay logic: repays several borrowers' loans at once, any invalid entry fails the whole batch
+fn batch_repay(deps: DepsMut, env: Env, info: MessageInfo, denom: String, repayments: Vec<(String, Uint128)>) -> Result<Response, ContractError> {
+    if repayments.len() > MAX_BATCH_SIZE {
+        return Err(ContractError::BatchTooLarge { max: MAX_BATCH_SIZE as u32 });
+    }
+    let now = env.block.time.seconds();
+    let config = CONFIG.load(deps.storage)?;
+    let total = repayments
+        .iter()
+        .try_fold(Uint128::zero(), |total, (_, amount)| total.checked_add(*amount))?;
+    ensure_paid(&info, &denom, total)?;
+
+    let mut response = Response::new().add_attribute("action", "batch_repay").add_attribute("denom", &denom);
+    let mut refund = Uint128::zero();
+    for (borrower, amount) in repayments {
+        if amount.is_zero() {
+            return Err(ContractError::ZeroAmount {});
+        }
+        let borrower = deps.api.addr_validate(&borrower)?;
+        let repayment = apply_repayment(deps.storage, &config, &borrower, &denom, amount, now, env.block.height)?;
+        refund = refund.checked_add(repayment.refund)?;
+        response = response
+            .add_event(repaid_event(&borrower, &denom, amount, &repayment, now))
+            .add_attribute("borrower", &borrower)
+            .add_attribute("interest_paid", repayment.interest_paid.to_string());
+    }
+    Ok(response.add_messages(refund_msg(&info.sender, &denom, refund)?))
+}
+
+// loan extension logic: paying exactly the accrued interest restarts the loan, and so its term, from now
+fn extend_loan(mut deps: DepsMut, env: Env, info: MessageInfo, denom: String) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    let now = env.block.time.seconds();
+    let config = CONFIG.load(deps.storage)?;
+    refresh_prices(deps.branch(), &config, &info.sender, now)?;
+    let loan = LOANS
+        .may_load(deps.storage, (&info.sender, denom.as_str()))?
+        .ok_or(ContractError::NoActiveLoan {})?;
+
+    // 只有健康的借款可以展期，抵押不足的借款應被清算
+    let debt = outstanding_debt(deps.as_ref(), &config, &info.sender, now, env.block.height)?;
+    if health_factor(deps.as_ref(), &config, &info.sender, debt, now)? < Decimal::one() {
+        return Err(ContractError::LoanUnderwater {});
+    }
+
+    let interest = total_due(&loan, &config, now, env.block.height)?.checked_sub(loan.amount_borrowed)?;
+    if interest.is_zero() {
+        if !info.funds.is_empty() {
+            return Err(ContractError::FundsMismatch { denom, amount: interest });
+        }
+    } else {
+        ensure_paid(&info, &denom, interest)?;
+    }
+    // 以應計利息做部分還款：本金不變，起算時間與借款期限都重設為現在
+    let repayment = apply_repayment(deps.storage, &config, &info.sender, &denom, interest, now, env.block.height)?;
+    let key = (&info.sender, denom.as_str());
+    let mut loan = LOANS.load(deps.storage, key)?;
+    loan.term_start_time = now;
+    save_loan(deps.storage, key, &loan)?;
+
+    Ok(Response::new()
+        .add_event(repaid_event(&info.sender, &denom, interest, &repayment, now))
+        .add_attribute("action", "extend_loan")
+        .add_attribute("denom", denom)
+        .add_attribute("interest_paid", repayment.interest_paid.to_string())
+        .add_attribute("loan_start_time", now.to_string()))
+}
+
+// refinancing logic: paying exactly the accrued interest on the borrow_denom loan re-snapshots it at the
+// rate a new loan would get now, which must be lower; like an extension, the loan restarts from now
+fn refinance(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    let now = env.block.time.seconds();
+    let config = CONFIG.load(deps.storage)?;
+    let denom = config.borrow_denom.clone();
+    let key = (&info.sender, denom.as_str());
+    let loan = LOANS
+        .may_load(deps.storage, key)?
+        .ok_or(ContractError::NoActiveLoan {})?;
+
+    let current = loan_rate(&loan, &config);
+    let offered = borrower_rate(deps.as_ref(), &config, &info.sender, &denom)?;
+    if offered >= current {
+        return Err(ContractError::RefinanceNotBeneficial { current, offered });
+    }
+
+    let interest = total_due(&loan, &config, now, env.block.height)?.checked_sub(loan.amount_borrowed)?;
+    if interest.is_zero() {
+        if !info.funds.is_empty() {
+            return Err(ContractError::FundsMismatch { denom, amount: interest });
+        }
+    } else {
+        ensure_paid(&info, &denom, interest)?;
+    }
+    let repayment = apply_repayment(deps.storage, &config, &info.sender, &denom, interest, now, env.block.height)?;
+    let mut loan = LOANS.load(deps.storage, key)?;
+    loan.interest_rate = offered;
+    loan.rate_mode = RateMode::Fixed;
+    loan.term_start_time = now;
+    save_loan(deps.storage, key, &loan)?;
+
+    Ok(Response::new()
+        .add_event(repaid_event(&info.sender, &denom, interest, &repayment, now))
+        .add_attribute("action", "refinance")
+        .add_attribute("denom", denom)
+        .add_attribute("interest_paid", repayment.interest_paid.to_string())
+        .add_attribute("old_rate", current.to_string())
+        .add_attribute("new_rate", offered.to_string()))
+}
+
+// message returning an overpayment, if any
+fn refund_msg(recipient: &Addr, denom: &str, amount: Uint128) -> StdResult<Option<BankMsg>> {
+    if amount.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(BankMsg::Send {
+        to_address: recipient.to_string(),
+        amount: vec![make_payout(amount, denom)?],
+    }))
+}
+
+// result of applying a repayment to a loan
+struct Repayment {
+    interest_rate: Decimal, //rate of the repaid loan
+    interest_paid: Uint128, //part of the repayment that covered interest
+    principal_paid: Uint128, //part of the repayment that covered principal
+    reserve: Uint128, //part of interest_paid credited to PROTOCOL_RESERVES
+    remaining_balance: Uint128, //amount still owed after the repayment
+    refund: Uint128, //part of the repayment exceeding the total due
+}
+
+// apply `amount` to `borrower`'s loan in `denom`, interest first and then principal, updating the protocol totals
+fn apply_repayment(storage: &mut dyn Storage, config: &Config, borrower: &Addr, denom: &str, amount: Uint128, now: u64, height: u64) -> Result<Repayment, ContractError> {
+    let loan = LOANS
+        .may_load(storage, (borrower, denom))?
+        .ok_or(ContractError::NoActiveLoan {})?;
+    let total_due = total_due(&loan, config, now, height)?;
+    let interest = total_due.checked_sub(loan.amount_borrowed)?;
+
+    let mut refund = Uint128::zero();
+    let mut remaining_balance = Uint128::zero();
+    if amount >= total_due {
+        remove_loan(storage, (borrower, denom))?;
+        sub_from_denom_total(storage, &TOTAL_BORROWED, denom, loan.amount_borrowed)?;
+        refund = amount.checked_sub(total_due)?;
+    } else {
+        // 部分還款：先償還利息，再償還本金，剩餘欠款從現在起重新計息，但借款期限不重新起算
+        let remaining = LoanInfo {
+            amount_borrowed: total_due.checked_sub(amount)?,
+            interest_rate: loan.interest_rate,
+            loan_start_time: now,
+            loan_start_height: height,
+            rate_mode: loan.rate_mode,
+            interest_free_until: loan.interest_free_until, // 部分還款不會重新開始免息期
+            term_seconds: loan.term_seconds,
+            term_start_time: term_start(&loan),
+        };
+        save_loan(storage, (borrower, denom), &remaining)?;
+        remaining_balance = remaining.amount_borrowed;
+        // 未付清的利息會併入本金
+        if remaining.amount_borrowed > loan.amount_borrowed {
+            add_to_denom_total(storage, &TOTAL_BORROWED, denom, remaining.amount_borrowed.checked_sub(loan.amount_borrowed)?)?;
+        } else {
+            sub_from_denom_total(storage, &TOTAL_BORROWED, denom, loan.amount_borrowed.checked_sub(remaining.amount_borrowed)?)?;
+        }
+    }
+
+    // 已付利息中 reserve_factor 的部分撥入該幣別的協議準備金
+    let interest_paid = amount.min(interest);
+    let principal_paid = amount.checked_sub(refund)?.checked_sub(interest_paid)?;
+    let reserve = mul_decimal(interest_paid, config.reserve_factor)?;
+    add_to_denom_total(storage, &PROTOCOL_RESERVES, denom, reserve)?;
+    add_to_denom_total(storage, &TOTAL_INTEREST_COLLECTED, denom, interest_paid)?;
+
+    Ok(Repayment { interest_rate: loan.interest_rate, interest_paid, principal_paid, reserve, remaining_balance, refund })
+}
+
+// loan_repaid event for one repayment
+fn repaid_event(borrower: &Addr, denom: &str, amount: Uint128, repayment: &Repayment, now: u64) -> Event {
+    Event::new("loan_repaid")
+        .add_attribute("borrower", borrower)
+        .add_attribute("denom", denom)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("interest_rate", repayment.interest_rate.to_string())
+        .add_attribute("interest_paid", repayment.interest_paid.to_string())
+        .add_attribute("timestamp", now.to_string())
+}
+
+// liquidation logic: anyone may repay one of an underwater borrower's loans and seize the collateral
+fn liquidate(mut deps: DepsMut, env: Env, info: MessageInfo, borrower: String, denom: String) -> Result<Response, ContractError> {
+    ensure_not_locked(deps.as_ref())?;
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let config = CONFIG.load(deps.storage)?;
+    let paid = info
+        .funds
+        .iter()
+        .filter(|fund| fund.denom == denom)
+        .try_fold(Uint128::zero(), |total, fund| total.checked_add(fund.amount))?;
+    let mut liquidation = liquidate_loan(deps.branch(), &env, &config, &info.sender, &borrower, &denom, paid)?;
+    if let Some(refund) = refund_msg(&info.sender, &denom, paid.saturating_sub(liquidation.repaid))? {
+        liquidation.messages.push(refund.into());
+    }
+
+    Ok(Response::new()
+        .add_submessages(lock_until_reply(deps.storage, liquidation.messages, RELEASE_LOCK_REPLY_ID)?)
+        .add_event(liquidation.event)
+        .add_attribute("action", "liquidate")
+        .add_attribute("borrower", borrower)
+        .add_attribute("liquidator", info.sender)
+        .add_attribute("debt_repaid", liquidation.repaid.to_string())
+        .add_attribute("collateral_seized", liquidation.seized.to_string())
+        .add_attribute("bonus", liquidation.bonus.to_string()))
+}
+
+// batch liquidation logic: liquidates up to `limit` (at most MAX_BATCH_SIZE) of the loans LiquidatableLoans
+// reports, paying for each out of the attached funds and refunding what is left; loans that cannot be
+// liquidated, because their borrower became healthy through an earlier liquidation in the batch or not enough
+// of their denom is attached, are skipped and reported in a liquidation_skipped event
+fn batch_liquidate(mut deps: DepsMut, env: Env, info: MessageInfo, limit: Option<u32>) -> Result<Response, ContractError> {
+    ensure_not_locked(deps.as_ref())?;
+    let config = CONFIG.load(deps.storage)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_BATCH_SIZE as u32);
+    let candidates = query_liquidatable_loans(deps.as_ref(), env.clone(), Some(limit))?.loans;
+
+    let mut remaining = info.funds.clone();
+    let mut messages = vec![];
+    let mut events = vec![];
+    let mut skipped = vec![];
+    for (borrower, denom, _) in candidates {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        // 未附上該幣別資金時視為可用金額為 0，由 liquidate_loan 回報資金不足並略過
+        let fund = remaining.iter().position(|fund| fund.denom == denom);
+        let available = fund.map(|i| remaining[i].amount).unwrap_or_default();
+        let liquidation = match liquidate_loan(deps.branch(), &env, &config, &info.sender, &borrower, &denom, available) {
+            Err(err @ (ContractError::LoanHealthy {} | ContractError::InsufficientFunds { .. })) => {
+                skipped.push(
+                    Event::new("liquidation_skipped")
+                        .add_attribute("borrower", borrower)
+                        .add_attribute("denom", denom)
+                        .add_attribute("reason", err.to_string()),
+                );
+                continue;
+            },
+            result => result?,
+        };
+        if let Some(i) = fund {
+            remaining[i].amount = remaining[i].amount.checked_sub(liquidation.repaid)?;
+        }
+        messages.extend(liquidation.messages);
+        events.push(liquidation.event);
+    }
+    for fund in remaining {
+        if let Some(refund) = refund_msg(&info.sender, &fund.denom, fund.amount)? {
+            messages.push(refund.into());
+        }
+    }
+
+    Ok(Response::new()
+        .add_submessages(lock_until_reply(deps.storage, messages, RELEASE_LOCK_REPLY_ID)?)
+        .add_attribute("action", "batch_liquidate")
+        .add_attribute("liquidator", info.sender)
+        .add_attribute("liquidated", events.len().to_string())
+        .add_attribute("skipped", skipped.len().to_string())
+        .add_events(events)
+        .add_events(skipped))
+}
+
+// outcome of liquidating one loan
+struct Liquidation {
+    repaid: Uint128, //debt repaid by the liquidator
+    seized: Uint128, //market value of the collateral sent to the liquidator
+    bonus: Uint128, //part of seized beyond the repaid debt
+    messages: Vec<CosmosMsg>, //collateral transfers to the liquidator
+    event: Event, //loan_liquidated event
+}
+
+// liquidate `borrower`'s loan in `denom` for `liquidator`, who has `available` of `denom` to repay with: repays
+// up to the close factor's share of it and seizes collateral worth the repayment plus the liquidation bonus
+fn liquidate_loan(mut deps: DepsMut, env: &Env, config: &Config, liquidator: &Addr, borrower: &Addr, denom: &str, available: Uint128) -> Result<Liquidation, ContractError> {
+    let loan = LOANS
+        .may_load(deps.storage, (borrower, denom))?
+        .ok_or(ContractError::NoActiveLoan {})?;
+    let now = env.block.time.seconds();
+    let total_due = total_due(&loan, config, now, env.block.height)?;
+
+    // 與 LiquidatableLoans 相同，健康係數（以價格與抵押係數折算）低於 1 才可清算
+    refresh_prices(deps.branch(), config, borrower, now)?;
+    let debt = outstanding_debt(deps.as_ref(), config, borrower, now, env.block.height)?;
+    if health_factor(deps.as_ref(), config, borrower, debt, now)? >= Decimal::one() {
+        return Err(ContractError::LoanHealthy {});
+    }
+
+    // 清算人代為償還該幣別欠款的 close_factor 比例，未設定（或小到取整為 0）時須償還全部
+    let mut repaid = mul_decimal(total_due, config.close_factor)?;
+    if repaid.is_zero() {
+        repaid = total_due;
+    }
+    if available < repaid {
+        return Err(ContractError::InsufficientFunds { denom: denom.to_string(), amount: repaid });
+    }
+    // 未償還的部分仍留在借款上，和一般部分還款相同
+    apply_repayment(deps.storage, config, borrower, denom, repaid, now, env.block.height)?;
+
+    // 清算人取得市值等於所償還欠款加上清算獎勵的抵押品，剩餘抵押品仍歸借款人所有；
+    // 償還金額依該幣別價格換算成 borrow_denom 價值
+    let repaid_value = token_value(deps.as_ref(), config, denom, repaid, now)?;
+    let bonus = mul_decimal(repaid_value, config.liquidation_bonus)?;
+    let (seized, messages) = seize_collateral(deps.branch(), config, borrower, liquidator, repaid_value.checked_add(bonus)?, now)?;
+
+    let event = Event::new("loan_liquidated")
+        .add_attribute("borrower", borrower)
+        .add_attribute("liquidator", liquidator)
+        .add_attribute("denom", denom)
+        .add_attribute("amount", repaid.to_string())
+        .add_attribute("interest_rate", loan.interest_rate.to_string())
+        .add_attribute("collateral_seized", seized.to_string())
+        .add_attribute("timestamp", now.to_string());
+    Ok(Liquidation { repaid, seized, bonus: seized.saturating_sub(repaid_value), messages, event })
+}
+
+// self-liquidation logic: the borrower settles every loan with their own collateral, which goes to the
+// treasury at market value with no bonus; any collateral left over is returned and any shortfall written off
+// as bad debt
+fn self_liquidate(mut deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    ensure_not_locked(deps.as_ref())?;
+    let config = CONFIG.load(deps.storage)?;
+    let borrower = info.sender;
+    let loans = LOANS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if loans.is_empty() {
+        return Err(ContractError::NoActiveLoan {});
+    }
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps.as_ref(), &config, &borrower, now, env.block.height)?;
+
+    for (denom, loan) in loans {
+        remove_loan(deps.storage, (&borrower, denom.as_str()))?;
+        sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &denom, loan.amount_borrowed)?;
+    }
+
+    // 欠款依價格與小數位數換算成抵押品數量轉入金庫，不足的部分記為壞帳
+    refresh_prices(deps.branch(), &config, &borrower, now)?;
+    let (seized, mut messages) = seize_collateral(deps.branch(), &config, &borrower, &config.treasury, debt, now)?;
+    let written_off = debt.checked_sub(seized)?;
+    write_off_debt(deps.storage, &config, written_off)?;
+
+    let positions = COLLATERALS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut returned = Uint128::zero();
+    for (token_address, collateral) in positions {
+        messages.push(collateral_transfer_msg(&borrower, &collateral, collateral.amount)?);
+        returned = returned.checked_add(token_value(deps.as_ref(), &config, &token_address, collateral.amount, now)?)?;
+        sub_from_total(deps.storage, &TOTAL_COLLATERAL, collateral.amount)?;
+        remove_collateral(deps.storage, (&borrower, token_address.as_str()))?;
+    }
+
+    Ok(Response::new()
+        .add_submessages(lock_until_reply(deps.storage, messages, RELEASE_LOCK_REPLY_ID)?)
+        .add_attribute("action", "self_liquidate")
+        .add_attribute("borrower", borrower)
+        .add_attribute("debt_settled", debt.to_string())
+        .add_attribute("collateral_seized", seized.to_string())
+        .add_attribute("collateral_returned", returned.to_string())
+        .add_attribute("debt_written_off", written_off.to_string()))
+}
+
+// stale collateral recovery logic (owner only): once any of a borrower's loans is past term by more
+// than the grace window and nobody liquidated it, the collateral goes to the treasury and all loans are closed;
+// whatever the collateral's market value does not cover is written off as bad debt
+fn recover_stale_collateral(deps: DepsMut, env: Env, info: MessageInfo, borrower: String) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let loans = LOANS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if loans.is_empty() {
+        return Err(ContractError::NoActiveLoan {});
+    }
+
+    // 無期限的借款永遠不會被視為廢棄
+    let is_stale = |loan: &LoanInfo| {
+        let term = loan_term(loan, &config);
+        let stale_after = term_start(loan)
+            .checked_add(term)
+            .and_then(|due| due.checked_add(config.recovery_grace_seconds));
+        matches!(stale_after, Some(stale_after) if term != 0 && env.block.time.seconds() > stale_after)
+    };
+    if !loans.iter().any(|(_, loan)| is_stale(loan)) {
+        return Err(ContractError::LoanNotStale {});
+    }
+
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps.as_ref(), &config, &borrower, now, env.block.height)?;
+    for (denom, loan) in loans {
+        remove_loan(deps.storage, (&borrower, denom.as_str()))?;
+        sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &denom, loan.amount_borrowed)?;
+    }
+
+    let positions = COLLATERALS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut messages = vec![];
+    let mut recovered = Uint128::zero();
+    for (token_address, collateral) in positions {
+        messages.push(collateral_transfer_msg(&config.treasury, &collateral, collateral.amount)?);
+        recovered = recovered.checked_add(token_value(deps.as_ref(), &config, &token_address, collateral.amount, now)?)?;
+        sub_from_total(deps.storage, &TOTAL_COLLATERAL, collateral.amount)?;
+        remove_collateral(deps.storage, (&borrower, token_address.as_str()))?;
+    }
+    // 回收的抵押品市值不足以抵銷的欠款記為壞帳
+    let written_off = debt.saturating_sub(recovered);
+    write_off_debt(deps.storage, &config, written_off)?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "recover_stale_collateral")
+        .add_attribute("borrower", borrower)
+        .add_attribute("treasury", config.treasury)
+        .add_attribute("debt_settled", debt.to_string())
+        .add_attribute("collateral_recovered", recovered.to_string())
+        .add_attribute("debt_written_off", written_off.to_string()))
+}
+
+// bad debt logic (owner only): once a borrower's collateral is gone, their remaining loans are written off,
+// absorbed by protocol reserves as far as they go and recorded in TOTAL_BAD_DEBT
+fn write_off_bad_debt(deps: DepsMut, env: Env, info: MessageInfo, borrower: String) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let loans = LOANS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if loans.is_empty() {
+        return Err(ContractError::NoActiveLoan {});
+    }
+    // 仍有抵押品時應先清算，而非直接沖銷
+    if !total_collateral(deps.as_ref(), &borrower)?.is_zero() {
+        return Err(ContractError::CollateralRemaining {});
+    }
+
+    let written_off = outstanding_debt(deps.as_ref(), &config, &borrower, env.block.time.seconds(), env.block.height)?;
+    for (denom, loan) in loans {
+        remove_loan(deps.storage, (&borrower, denom.as_str()))?;
+        sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &denom, loan.amount_borrowed)?;
+    }
+    let reserves_used = write_off_debt(deps.storage, &config, written_off)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "write_off_bad_debt")
+        .add_attribute("borrower", borrower)
+        .add_attribute("written_off", written_off.to_string())
+        .add_attribute("reserves_used", reserves_used.to_string()))
+}
+
+// loan transfer logic: proposes moving every loan and collateral position of the sender to `new_borrower`,
+// which takes effect once `new_borrower` accepts; proposing again replaces the earlier proposal
+fn transfer_loan(deps: DepsMut, info: MessageInfo, new_borrower: String) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    ensure_not_locked(deps.as_ref())?;
+    let new_borrower = deps.api.addr_validate(&new_borrower)?;
+    if LOANS.prefix(&info.sender).range(deps.storage, None, None, Order::Ascending).next().is_none() {
+        return Err(ContractError::NoActiveLoan {});
+    }
+    ensure_no_loan(deps.as_ref(), &new_borrower)?;
+    PENDING_LOAN_TRANSFERS.save(deps.storage, &info.sender, &new_borrower)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "transfer_loan")
+        .add_attribute("from", info.sender)
+        .add_attribute("pending_borrower", new_borrower))
+}
+
+// accept loan transfer logic (proposed new borrower only): moves `from`'s loans as they stand now, together
+// with their collateral, which is merged with any collateral the sender already holds
+fn accept_loan_transfer(deps: DepsMut, info: MessageInfo, from: String) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.as_ref())?;
+    ensure_not_locked(deps.as_ref())?;
+    let from = deps.api.addr_validate(&from)?;
+    if PENDING_LOAN_TRANSFERS.may_load(deps.storage, &from)?.as_ref() != Some(&info.sender) {
+        return Err(ContractError::NotPendingBorrower { from: from.to_string() });
+    }
+    let new_borrower = info.sender;
+    let loans = LOANS
+        .prefix(&from)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if loans.is_empty() {
+        return Err(ContractError::NoActiveLoan {});
+    }
+    ensure_no_loan(deps.as_ref(), &new_borrower)?;
+    PENDING_LOAN_TRANSFERS.remove(deps.storage, &from);
+
+    for (denom, loan) in &loans {
+        remove_loan(deps.storage, (&from, denom.as_str()))?;
+        save_loan(deps.storage, (&new_borrower, denom.as_str()), loan)?;
+    }
+    let positions = COLLATERALS
+        .prefix(&from)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (token_address, mut collateral) in positions {
+        remove_collateral(deps.storage, (&from, token_address.as_str()))?;
+        if let Some(existing) = COLLATERALS.may_load(deps.storage, (&new_borrower, token_address.as_str()))? {
+            collateral.amount = collateral.amount.checked_add(existing.amount)?;
+        }
+        save_collateral(deps.storage, (&new_borrower, token_address.as_str()), &collateral)?;
+    }
+    // 借款冷卻期跟著借款移轉，換地址不能規避
+    if let Some(last) = LAST_BORROW.may_load(deps.storage, &from)? {
+        LAST_BORROW.remove(deps.storage, &from);
+        LAST_BORROW.save(deps.storage, &new_borrower, &last)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_loan_transfer")
+        .add_attribute("from", from)
+        .add_attribute("to", new_borrower)
+        .add_attribute("loans", loans.len().to_string()))
+}
+
+// reject `borrower` as a loan transfer destination while it has a loan of its own, so two loans are never merged
+fn ensure_no_loan(deps: Deps, borrower: &Addr) -> Result<(), ContractError> {
+    if LOANS.prefix(borrower).range(deps.storage, None, None, Order::Ascending).next().is_some() {
+        return Err(ContractError::LoanAlreadyExists {});
+    }
+    Ok(())
+}
+
+// dust loan logic (owner only): closes a loan too small to be worth repaying, writing its balance off against
+// the reserves
+fn close_dust_loan(deps: DepsMut, env: Env, info: MessageInfo, borrower: String, denom: String) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let key = (&borrower, denom.as_str());
+    let loan = LOANS
+        .may_load(deps.storage, key)?
+        .ok_or(ContractError::NoActiveLoan {})?;
+    let balance = total_due(&loan, &config, env.block.time.seconds(), env.block.height)?;
+    if balance >= config.dust_threshold {
+        return Err(ContractError::LoanNotDust { threshold: config.dust_threshold });
+    }
+
+    // 與其他沖銷相同，欠款依價格換算成 borrow_denom 後由準備金全額吸收並記為壞帳
+    let written_off = token_value(deps.as_ref(), &config, &denom, balance, env.block.time.seconds())?;
+    let available = PROTOCOL_RESERVES.may_load(deps.storage, &config.borrow_denom)?.unwrap_or_default();
+    if written_off > available {
+        return Err(ContractError::InsufficientReserves { available });
+    }
+    remove_loan(deps.storage, key)?;
+    sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &denom, loan.amount_borrowed)?;
+    write_off_debt(deps.storage, &config, written_off)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "close_dust_loan")
+        .add_attribute("borrower", borrower)
+        .add_attribute("denom", denom)
+        .add_attribute("written_off", written_off.to_string()))
+}
+
+// reserve repayment logic (owner only): clears a borrower's borrow_denom loan in full out of protocol reserves
+fn repay_from_reserves(deps: DepsMut, env: Env, info: MessageInfo, borrower: String) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let key = (&borrower, config.borrow_denom.as_str());
+    let loan = LOANS
+        .may_load(deps.storage, key)?
+        .ok_or(ContractError::NoActiveLoan {})?;
+    let balance = total_due(&loan, &config, env.block.time.seconds(), env.block.height)?;
+
+    // 準備金須足以吸收全部欠款，結清的欠款與其他沖銷一樣記為壞帳
+    let available = PROTOCOL_RESERVES.may_load(deps.storage, &config.borrow_denom)?.unwrap_or_default();
+    if balance > available {
+        return Err(ContractError::InsufficientReserves { available });
+    }
+    remove_loan(deps.storage, key)?;
+    sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &config.borrow_denom, loan.amount_borrowed)?;
+    write_off_debt(deps.storage, &config, balance)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "repay_from_reserves")
+        .add_attribute("borrower", borrower)
+        .add_attribute("denom", config.borrow_denom)
+        .add_attribute("repaid", balance.to_string()))
+}
+
+// Implements interest rate update logic (owner only)
+fn update_interest_rate(deps: DepsMut, env: Env, info: MessageInfo, new_rate: Decimal) -> Result<Response, ContractError> {
+    // Verify if the sender is the owner
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    ensure_valid_interest_rate(new_rate)?;
+
+    // Update the interest rate
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.base_interest_rate = new_rate;
+        Ok(conf)
+    })?;
+    record_rate_change(deps.storage, &env, new_rate)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_interest_rate")
+        .add_attribute("new_rate", new_rate.to_string()))
+}
+
+// pause / unpause logic (owner only)
+fn set_paused(deps: DepsMut, info: MessageInfo, paused: bool) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.paused = paused;
+        Ok(conf)
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_paused")
+        .add_attribute("paused", paused.to_string()))
+}
+
+// propose owner logic (owner only), the new owner must accept before taking over
+fn propose_new_owner(deps: DepsMut, info: MessageInfo, new_owner: String) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let new_owner = deps.api.addr_validate(&new_owner)?;
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.pending_owner = Some(new_owner.clone());
+        Ok(conf)
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "propose_new_owner")
+        .add_attribute("pending_owner", new_owner))
+}
+
+// accept ownership logic (pending owner only)
+fn accept_ownership(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.pending_owner.as_ref() != Some(&info.sender) {
+        return Err(ContractError::NotPendingOwner {});
+    }
+
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.owner = info.sender.clone();
+        conf.pending_owner = None;
+        Ok(conf)
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_ownership")
+        .add_attribute("owner", info.sender))
+}
+
+// liquidation bonus logic (owner only)
+fn set_liquidation_bonus(deps: DepsMut, info: MessageInfo, bonus: Decimal) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    ensure_valid_liquidation_bonus(bonus)?;
+
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.liquidation_bonus = bonus;
+        Ok(conf)
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_liquidation_bonus")
+        .add_attribute("bonus", bonus.to_string()))
+}
+
+// collateral factor logic (owner only)
+fn set_collateral_factor(deps: DepsMut, info: MessageInfo, token_address: String, factor: Decimal) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if factor > Decimal::one() {
+        return Err(ContractError::InvalidCollateralFactor {});
+    }
+
+    COLLATERAL_FACTORS.save(deps.storage, token_address.clone(), &factor)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_collateral_factor")
+        .add_attribute("token_address", token_address)
+        .add_attribute("factor", factor.to_string()))
+}
+
+// token rate logic (owner only): overrides the base rate for loans backed by `token_address`, or clears the override
+fn set_token_rate(deps: DepsMut, info: MessageInfo, token_address: String, rate: Option<Decimal>) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    match rate {
+        Some(rate) => {
+            ensure_valid_interest_rate(rate)?;
+            TOKEN_RATES.save(deps.storage, token_address.clone(), &rate)?;
+        },
+        None => TOKEN_RATES.remove(deps.storage, token_address.clone()),
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "set_token_rate")
+        .add_attribute("token_address", token_address)
+        .add_attribute("rate", rate.map(|rate| rate.to_string()).unwrap_or_else(|| "none".to_string())))
+}
+
+// token decimals logic (owner only), used to value collateral tokens on the borrow denom's scale
+fn set_token_decimals(deps: DepsMut, info: MessageInfo, token_address: String, decimals: u8) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if decimals > MAX_DECIMALS {
+        return Err(ContractError::InvalidDecimals { max: MAX_DECIMALS });
+    }
+
+    TOKEN_DECIMALS.save(deps.storage, token_address.clone(), &decimals)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_token_decimals")
+        .add_attribute("token_address", token_address)
+        .add_attribute("decimals", decimals.to_string()))
+}
+
+// collateral whitelist logic (owner only)
+fn set_token_allowed(deps: DepsMut, info: MessageInfo, token_address: String, allowed: bool) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if allowed {
+        ALLOWED_TOKENS.save(deps.storage, token_address.clone(), &true)?;
+    } else {
+        ALLOWED_TOKENS.remove(deps.storage, token_address.clone());
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", if allowed { "allow_token" } else { "disallow_token" })
+        .add_attribute("token_address", token_address))
+}
+
+// minimum collateral deposit logic (owner only)
+fn set_min_collateral_amount(deps: DepsMut, info: MessageInfo, amount: Uint128) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+        conf.min_collateral_amount = amount;
+        Ok(conf)
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_min_collateral_amount")
+        .add_attribute("amount", amount.to_string()))
+}
+
+// batched config update logic (owner only): applies only the fields that are set
+fn update_config(deps: DepsMut, info: MessageInfo, update: UpdateConfigMsg) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if update == UpdateConfigMsg::default() {
+        return Err(ContractError::EmptyConfigUpdate {});
+    }
+    if update.reserve_factor.is_some_and(|factor| factor > Decimal::one()) {
+        return Err(ContractError::InvalidReserveFactor {});
+    }
+    if update.close_factor.is_some_and(|factor| factor > Decimal::one()) {
+        return Err(ContractError::InvalidCloseFactor {});
+    }
+    if update.origination_fee.is_some_and(|fee| fee > Decimal::one()) {
+        return Err(ContractError::InvalidOriginationFee {});
+    }
+    if let Some(bonus) = update.liquidation_bonus {
+        ensure_valid_liquidation_bonus(bonus)?;
+    }
+
+    let mut response = Response::new().add_attribute("action", "update_config");
+    macro_rules! apply {
+        ($($field:ident),*) => {$(
+            if let Some(value) = update.$field {
+                response = response.add_attribute(stringify!($field), value.to_string());
+                config.$field = value;
+            }
+        )*};
+    }
+    apply!(
+        max_ltv, liquidation_threshold, loan_term_seconds, penalty_rate, reserve_factor, slope,
+        max_borrow_per_user, min_collateral_amount, recovery_grace_seconds, origination_fee,
+        dust_threshold, borrow_cooldown_seconds, compound, liquidation_bonus, grace_interest_free_seconds,
+        price_max_age_seconds, min_loan_seconds, max_loan_seconds, close_factor
+    );
+    ensure_valid_loan_bounds(config.min_loan_seconds, config.max_loan_seconds)?;
+    if let Some(treasury) = update.treasury {
+        config.treasury = deps.api.addr_validate(&treasury)?;
+        response = response.add_attribute("treasury", treasury);
+    }
+    if let Some(oracle) = update.oracle {
+        config.oracle = Some(deps.api.addr_validate(&oracle)?);
+        response = response.add_attribute("oracle", oracle);
+    }
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(response)
+}
+
+// reserve withdrawal logic (owner only), paid out to the treasury in the reserves' denom
+fn withdraw_reserves(deps: DepsMut, info: MessageInfo, amount: Uint128, denom: Option<String>) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    let denom = denom.unwrap_or_else(|| config.borrow_denom.clone());
+    let available = PROTOCOL_RESERVES.may_load(deps.storage, &denom)?.unwrap_or_default();
+    if amount > available {
+        return Err(ContractError::InsufficientReserves { available });
+    }
+
+    sub_from_denom_total(deps.storage, &PROTOCOL_RESERVES, &denom, amount)?;
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: config.treasury.to_string(),
+            amount: vec![make_payout(amount, &denom)?],
+        })
+        .add_attribute("action", "withdraw_reserves")
+        .add_attribute("denom", denom)
+        .add_attribute("recipient", config.treasury)
+        .add_attribute("amount", amount.to_string()))
+}
+
+// provide liquidity logic (owner only): credits the attached coin to the lendable pool of its denom
+fn provide_liquidity(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let (denom, amount) = match info.funds.as_slice() {
+        [fund] if !fund.amount.is_zero() => (fund.denom.clone(), fund.amount),
+        _ => return Err(ContractError::InvalidLiquidityDeposit {}),
+    };
+    add_to_denom_total(deps.storage, &TOTAL_LIQUIDITY, &denom, amount)?;
+
+    // 新增流動性後利用率下降，回報該幣別新借款此刻會採用的年利率
+    Ok(Response::new()
+        .add_attribute("action", "provide_liquidity")
+        .add_attribute("denom", &denom)
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("utilization", utilization(deps.as_ref(), &denom)?.to_string())
+        .add_attribute("current_borrow_rate", current_borrow_rate(deps.as_ref(), &config, &denom)?.to_string()))
+}
+
+// remove liquidity logic (owner only): only the part of the denom's pool not lent out can be withdrawn
+fn remove_liquidity(deps: DepsMut, info: MessageInfo, amount: Uint128, denom: Option<String>) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    let denom = denom.unwrap_or(config.borrow_denom);
+    let liquidity = TOTAL_LIQUIDITY.may_load(deps.storage, &denom)?.unwrap_or_default();
+    let borrowed = TOTAL_BORROWED.may_load(deps.storage, &denom)?.unwrap_or_default();
+    let available = liquidity.saturating_sub(borrowed);
+    if amount > available {
+        return Err(ContractError::InsufficientLiquidity { available });
+    }
+    sub_from_denom_total(deps.storage, &TOTAL_LIQUIDITY, &denom, amount)?;
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: vec![coin(amount.u128(), &denom)],
+        })
+        .add_attribute("action", "remove_liquidity")
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("utilization", utilization(deps.as_ref(), &denom)?.to_string())
+        .add_attribute("denom", denom))
+}
+
+// sudo logic, no owner check since only the chain can call it
+pub fn sudo(deps: DepsMut, env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::SetBaseRate { rate } => {
+            ensure_valid_interest_rate(rate)?;
+            CONFIG.update(deps.storage, |mut conf| -> StdResult<_> {
+                conf.base_interest_rate = rate;
+                Ok(conf)
+            })?;
+            record_rate_change(deps.storage, &env, rate)?;
+            Ok(Response::new()
+                .add_attribute("action", "sudo_set_base_rate")
+                .add_attribute("new_rate", rate.to_string()))
+        },
+    }
+}
+
+// reply logic: the last transfer of a guarded operation reports back here
+pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
+    REENTRANCY_LOCK.remove(deps.storage);
+    match (msg.id, msg.result) {
+        (RELEASE_LOCK_REPLY_ID, SubMsgResult::Err(err)) => {
+            // 清算轉帳失敗時中止整筆交易，讓借款、抵押品等狀態一併回滾
+            Err(StdError::generic_err(err).into())
+        },
+        (RELEASE_LOCK_REPLY_ID, SubMsgResult::Ok(_)) => {
+            Ok(Response::new().add_attribute("action", "release_lock"))
+        },
+        (BORROW_REPLY_ID | WITHDRAW_REPLY_ID, result) => {
+            let pending = PENDING_TRANSFER.load(deps.storage)?;
+            PENDING_TRANSFER.remove(deps.storage);
+            match result {
+                SubMsgResult::Ok(_) if msg.id == BORROW_REPLY_ID && !pending.fee.is_zero() => {
+                    // 撥款成功後才將手續費轉給金庫，撥款失敗時手續費不會離開合約
+                    let config = CONFIG.load(deps.storage)?;
+                    Ok(Response::new()
+                        .add_message(BankMsg::Send {
+                            to_address: config.treasury.to_string(),
+                            amount: vec![make_payout(pending.fee, &pending.token_address)?],
+                        })
+                        .add_attribute("action", "release_lock")
+                        .add_attribute("fee_to_treasury", pending.fee.to_string()))
+                },
+                SubMsgResult::Ok(_) => Ok(Response::new().add_attribute("action", "release_lock")),
+                SubMsgResult::Err(err) if msg.id == BORROW_REPLY_ID => rollback_borrow(deps, pending, err),
+                SubMsgResult::Err(err) => rollback_withdraw(deps, pending, err),
+            }
+        },
+        (id, _) => Err(ContractError::UnknownReplyId { id }),
+    }
+}
+
+// undo a borrow whose payout failed: the loan was never funded
+fn rollback_borrow(deps: DepsMut, pending: PendingTransfer, err: String) -> Result<Response, ContractError> {
+    remove_loan(deps.storage, (&pending.owner, pending.token_address.as_str()))?;
+    sub_from_denom_total(deps.storage, &TOTAL_BORROWED, &pending.token_address, pending.amount)?;
+    // 撥款失敗視同未處理，同一 request_id 可以重試
+    if let Some(request_id) = &pending.request_id {
+        PROCESSED_BORROWS.remove(deps.storage, (&pending.owner, request_id.as_str()));
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "rollback_borrow")
+        .add_attribute("borrower", pending.owner)
+        .add_attribute("amount", pending.amount.to_string())
+        .add_attribute("error", err))
+}
+
+// undo a withdrawal whose transfer failed: the collateral never left the contract
+fn rollback_withdraw(deps: DepsMut, pending: PendingTransfer, err: String) -> Result<Response, ContractError> {
+    let key = (&pending.owner, pending.token_address.as_str());
+    let mut collateral = COLLATERALS.may_load(deps.storage, key)?.unwrap_or(Collateral {
+        token_address: pending.token_address.clone(),
+        amount: Uint128::zero(),
+        cw20: pending.cw20,
+        deposited_at: pending.deposited_at,
+    });
+    collateral.amount = collateral.amount.checked_add(pending.amount)?;
+    save_collateral(deps.storage, key, &collateral)?;
+    add_to_total(deps.storage, &TOTAL_COLLATERAL, pending.amount)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "rollback_withdraw_collateral")
+        .add_attribute("owner", pending.owner)
+        .add_attribute("token_address", pending.token_address)
+        .add_attribute("amount", pending.amount.to_string())
+        .add_attribute("error", err))
+}
+
+// query contract state
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let res = match msg {
+        QueryMsg::GetLoan { borrower, denom } => to_json_binary(&query_loan(deps, env, borrower, denom)?),
+        QueryMsg::GetCollateral { borrower } => to_json_binary(&query_collateral(deps, borrower)?),
+        QueryMsg::GetConfig {} => to_json_binary(&query_config(deps)?),
+        QueryMsg::ListLoans { start_after, limit, denom } => to_json_binary(&query_list_loans(deps, start_after, limit, denom)?),
+        QueryMsg::GetStats { denom } => to_json_binary(&query_stats(deps, denom)?),
+        QueryMsg::HealthFactor { borrower } => to_json_binary(&query_health_factor(deps, env, borrower)?),
+        QueryMsg::ListCollaterals { start_after, limit } => to_json_binary(&query_list_collaterals(deps, start_after, limit)?),
+        QueryMsg::GetOwner {} => to_json_binary(&query_owner(deps)?),
+        QueryMsg::IsOwner { address } => to_json_binary(&query_is_owner(deps, address)?),
+        QueryMsg::SimulateBorrow { borrower, denom, amount } => to_json_binary(&query_simulate_borrow(deps, env, borrower, denom, amount)?),
+        QueryMsg::RateHistory { start_after, limit } => to_json_binary(&query_rate_history(deps, start_after, limit)?),
+        QueryMsg::Reserves { denom } => to_json_binary(&query_reserves(deps, denom)?),
+        QueryMsg::BorrowCapacity { borrower } => to_json_binary(&query_borrow_capacity(deps, env, borrower)?),
+        QueryMsg::LiquidatableLoans { limit } => to_json_binary(&query_liquidatable_loans(deps, env, limit)?),
+        QueryMsg::SolvencyCheck {} => to_json_binary(&query_solvency(deps, env)?),
+        QueryMsg::LoanCount {} => to_json_binary(&CountResponse { count: LOAN_COUNT.may_load(deps.storage)?.unwrap_or_default() }),
+        QueryMsg::CurrentBorrowRate { borrower, denom } => to_json_binary(&query_current_borrow_rate(deps, borrower, denom)?),
+        QueryMsg::CollateralByToken {} => {
+            let totals = COLLATERAL_TOTALS.range(deps.storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+            to_json_binary(&CollateralByTokenResponse { totals })
+        },
+        QueryMsg::Version {} => {
+            let version = cw2::get_contract_version(deps.storage)?;
+            to_json_binary(&VersionResponse { contract: version.contract, version: version.version })
+        },
+        QueryMsg::CollateralCount {} => to_json_binary(&CountResponse { count: COLLATERAL_COUNT.may_load(deps.storage)?.unwrap_or_default() }),
+        QueryMsg::WithdrawableCollateral { borrower, token_address } => {
+            to_json_binary(&query_withdrawable_collateral(deps, env, borrower, token_address)?)
+        },
+    }?;
+    Ok(res)
+}
+
+// loan query logic
+fn query_loan(deps: Deps, env: Env, borrower: String, denom: String) -> Result<LoanResponse, ContractError> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let loan = LOANS
+        .may_load(deps.storage, (&borrower, denom.as_str()))?
+        .ok_or(ContractError::NoActiveLoan {})?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let total_due = total_due(&loan, &config, now, env.block.height)?;
+    // 與罰息相同的判斷：自借款期限起算時間經過超過借款期限才算逾期
+    let term = loan_term(&loan, &config);
+    let due = term_start(&loan).saturating_add(term);
+    let seconds_until_due = (term != 0).then(|| due.saturating_sub(now));
+    let is_overdue = term != 0 && now > due;
+    Ok(LoanResponse {
+        amount_borrowed: loan.amount_borrowed,
+        interest_rate: loan.interest_rate,
+        loan_start_time: loan.loan_start_time,
+        loan_start_height: loan.loan_start_height,
+        accrued_interest: total_due.checked_sub(loan.amount_borrowed)?,
+        total_due,
+        rate_mode: loan.rate_mode,
+        is_overdue,
+        seconds_until_due,
+    })
+}
+
+// collateral query logic
+fn query_collateral(deps: Deps, borrower: String) -> StdResult<CollateralResponse> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let collaterals = COLLATERALS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, collateral)| collateral))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(CollateralResponse { collaterals })
+}
+
+// list loans query logic, pages over the (borrower, denom) key, optionally keeping only loans in `denom`
+fn query_list_loans(deps: Deps, start_after: Option<(String, String)>, limit: Option<u32>, denom: Option<String>) -> StdResult<LoansResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after
+        .map(|(borrower, denom)| deps.api.addr_validate(&borrower).map(|borrower| (borrower, denom)))
+        .transpose()?;
+    let start = start_after.as_ref().map(|(borrower, denom)| Bound::exclusive((borrower, denom.as_str())));
+    let loans = LOANS
+        .range(deps.storage, start, None, Order::Ascending)
+        .filter(|item| match (item, &denom) {
+            (Ok(((_, loan_denom), _)), Some(denom)) => loan_denom == denom,
+            _ => true,
+        })
+        .take(limit)
+        .map(|item| item.map(|((borrower, denom), loan)| (borrower.to_string(), denom, loan)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(LoansResponse { loans })
+}
+
+// list collaterals query logic, pages over the (owner, token_address) key
+fn query_list_collaterals(deps: Deps, start_after: Option<(String, String)>, limit: Option<u32>) -> StdResult<CollateralsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after
+        .map(|(owner, token)| deps.api.addr_validate(&owner).map(|owner| (owner, token)))
+        .transpose()?;
+    let start = start_after.as_ref().map(|(owner, token)| Bound::exclusive((owner, token.as_str())));
+    let collaterals = COLLATERALS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|((owner, token), collateral)| (owner.to_string(), token, collateral.amount)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(CollateralsResponse { collaterals })
+}
+
+// protocol stats query logic, borrowed and interest totals are for `denom` (borrow_denom by default)
+fn query_stats(deps: Deps, denom: Option<String>) -> StdResult<StatsResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let denom = denom.unwrap_or(config.borrow_denom);
+    Ok(StatsResponse {
+        total_borrowed: TOTAL_BORROWED.may_load(deps.storage, &denom)?.unwrap_or_default(),
+        total_collateral: TOTAL_COLLATERAL.may_load(deps.storage)?.unwrap_or_default(),
+        base_interest_rate: config.base_interest_rate,
+        total_interest_collected: TOTAL_INTEREST_COLLECTED.may_load(deps.storage, &denom)?.unwrap_or_default(),
+        total_bad_debt: TOTAL_BAD_DEBT.may_load(deps.storage)?.unwrap_or_default(),
+    })
+}
+
+// current borrow rate query logic: with a borrower, the rate their next borrow would snapshot (see borrower_rate)
+fn query_current_borrow_rate(deps: Deps, borrower: Option<String>, denom: Option<String>) -> Result<BorrowRateResponse, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let denom = denom.unwrap_or_else(|| config.borrow_denom.clone());
+    let rate = match borrower {
+        Some(borrower) => borrower_rate(deps, &config, &deps.api.addr_validate(&borrower)?, &denom)?,
+        None => current_borrow_rate(deps, &config, &denom)?,
+    };
+    Ok(BorrowRateResponse {
+        rate,
+        utilization: utilization(deps, &denom)?,
+    })
+}
+
+// health factor query logic
+fn query_health_factor(deps: Deps, env: Env, borrower: String) -> Result<HealthFactorResponse, ContractError> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps, &config, &borrower, now, env.block.height)?;
+    Ok(HealthFactorResponse { health_factor: health_factor(deps, &config, &borrower, debt, now)? })
+}
+
+// simulate borrow query logic, runs the same checks as borrow without touching state
+fn query_simulate_borrow(deps: Deps, env: Env, borrower: String, denom: String, amount: Uint128) -> Result<SimulateBorrowResponse, ContractError> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let check = ensure_not_paused(deps).and_then(|_| check_borrow(deps, &config, &borrower, &denom, amount, now));
+
+    // 新借款加上既有各幣別借款的欠款後的健康係數
+    let debt = outstanding_debt(deps, &config, &borrower, now, env.block.height)?.checked_add(token_value(deps, &config, &denom, amount, now)?)?;
+    Ok(SimulateBorrowResponse {
+        allowed: check.is_ok(),
+        error: check.err().map(|err| err.to_string()),
+        interest_rate: borrower_rate(deps, &config, &borrower, &denom)?,
+        health_factor: health_factor(deps, &config, &borrower, debt, now)?,
+    })
+}
+
+// rate history query logic
+fn query_rate_history(deps: Deps, start_after: Option<u64>, limit: Option<u32>) -> StdResult<RateHistoryResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let history = RATE_HISTORY
+        .range(deps.storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(RateHistoryResponse { history })
+}
+
+// reserves query logic, for `denom` (borrow_denom by default)
+fn query_reserves(deps: Deps, denom: Option<String>) -> StdResult<ReservesResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let denom = denom.unwrap_or(config.borrow_denom);
+    Ok(ReservesResponse {
+        reserves: PROTOCOL_RESERVES.may_load(deps.storage, &denom)?.unwrap_or_default(),
+        reserve_factor: config.reserve_factor,
+    })
+}
+
+// liquidatable loans query logic: scans LOANS and keeps those whose borrower is below a health factor of 1
+fn query_liquidatable_loans(deps: Deps, env: Env, limit: Option<u32>) -> Result<LiquidatableLoansResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let mut loans = vec![];
+    for item in LOANS.keys(deps.storage, None, None, Order::Ascending) {
+        if loans.len() == limit {
+            break;
+        }
+        let (borrower, denom) = item?;
+        let debt = outstanding_debt(deps, &config, &borrower, now, env.block.height)?;
+        let health_factor = health_factor(deps, &config, &borrower, debt, now)?;
+        if health_factor < Decimal::one() {
+            loans.push((borrower.to_string(), denom, health_factor));
+        }
+    }
+    Ok(LiquidatableLoansResponse { loans })
+}
+
+// solvency check query logic. Iterates every collateral position and every loan, so its gas cost
+// grows with the number of users; meant for off-chain monitoring where queries are not metered per
+// transaction, and will hit the node's query gas limit once the contract holds enough positions
+fn query_solvency(deps: Deps, env: Env) -> Result<SolvencyResponse, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    // 與健康係數相同，各部位依價格、小數位數與抵押係數折算後加總
+    let total_collateral_value = COLLATERALS
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| -> Result<_, ContractError> {
+            let ((_, token_address), collateral) = item?;
+            Ok(total.checked_add(weighted_value(deps, &config, &token_address, collateral.amount, now)?)?)
+        })?;
+    let total_outstanding_debt = LOANS
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |total, item| -> Result<_, ContractError> { Ok(total.checked_add(total_due(&item?.1, &config, now, env.block.height)?)?) })?;
+    Ok(SolvencyResponse {
+        solvent: mul_decimal(total_collateral_value, config.liquidation_threshold)? >= total_outstanding_debt,
+        total_collateral_value,
+        total_outstanding_debt,
+    })
+}
+
+// withdrawable collateral query logic: the remaining collateral value (see weighted_value) must still back
+// the debt at max_ltv (as withdraw_collateral requires) and keep it within the liquidation threshold
+fn query_withdrawable_collateral(deps: Deps, env: Env, borrower: String, token_address: String) -> Result<WithdrawableCollateralResponse, ContractError> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let position = COLLATERALS
+        .may_load(deps.storage, (&borrower, token_address.as_str()))?
+        .map(|collateral| collateral.amount)
+        .unwrap_or_default();
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let debt = outstanding_debt(deps, &config, &borrower, now, env.block.height)?;
+    if debt.is_zero() {
+        return Ok(WithdrawableCollateralResponse { amount: position });
+    }
+    if config.max_ltv.is_zero() || config.liquidation_threshold.is_zero() {
+        return Ok(WithdrawableCollateralResponse { amount: Uint128::zero() });
+    }
+
+    // 其他部位不足以支撐的價值，須由此部位保留足夠數量補足
+    let required = debt.checked_div_ceil(config.max_ltv)?.max(debt.checked_div_ceil(config.liquidation_threshold)?);
+    let position_value = weighted_value(deps, &config, &token_address, position, now)?;
+    let others = collateral_value(deps, &config, &borrower, now)?.checked_sub(position_value)?;
+    let needed = required.saturating_sub(others);
+    if needed.is_zero() {
+        return Ok(WithdrawableCollateralResponse { amount: position });
+    }
+    let amount = match weighted_amount(deps, &config, &token_address, needed, now)? {
+        Some(keep) => position.saturating_sub(keep),
+        None => Uint128::zero(),
+    };
+    Ok(WithdrawableCollateralResponse { amount })
+}
+
+// borrow capacity query logic
+fn query_borrow_capacity(deps: Deps, env: Env, borrower: String) -> Result<BorrowCapacityResponse, ContractError> {
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let config = CONFIG.load(deps.storage)?;
+    Ok(BorrowCapacityResponse {
+        capacity: borrow_capacity(deps, &config, &borrower, env.block.time.seconds(), env.block.height)?,
+    })
+}
+
+// owner query logic
+fn query_owner(deps: Deps) -> StdResult<OwnerResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(OwnerResponse { owner: config.owner })
+}
+
+// is-owner query logic
+fn query_is_owner(deps: Deps, address: String) -> StdResult<IsOwnerResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let config = CONFIG.load(deps.storage)?;
+    Ok(IsOwnerResponse { is_owner: address == config.owner })
+}
+
+// config query logic
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(ConfigResponse {
+        owner: config.owner,
+        base_interest_rate: config.base_interest_rate,
+        max_ltv: config.max_ltv,
+        liquidation_threshold: config.liquidation_threshold,
+        paused: config.paused,
+        pending_owner: config.pending_owner,
+        borrow_denom: config.borrow_denom,
+        loan_term_seconds: config.loan_term_seconds,
+        penalty_rate: config.penalty_rate,
+        reserve_factor: config.reserve_factor,
+        slope: config.slope,
+        max_borrow_per_user: config.max_borrow_per_user,
+        min_collateral_amount: config.min_collateral_amount,
+        treasury: config.treasury,
+        recovery_grace_seconds: config.recovery_grace_seconds,
+        origination_fee: config.origination_fee,
+        dust_threshold: config.dust_threshold,
+        borrow_cooldown_seconds: config.borrow_cooldown_seconds,
+        compound: config.compound,
+        liquidation_bonus: config.liquidation_bonus,
+        grace_interest_free_seconds: config.grace_interest_free_seconds,
+        price_max_age_seconds: config.price_max_age_seconds,
+        min_loan_seconds: config.min_loan_seconds,
+        max_loan_seconds: config.max_loan_seconds,
+        close_factor: config.close_factor,
+        accrual_mode: config.accrual_mode,
+        oracle: config.oracle,
+    })
+}
+
+//Possible Issues:
 //
 // Permission Control:
 // The contract does not explicitly control permissions for certain operations
 // (such as changing interest rates or withdrawing collateral), potentially allowing anyone to
 // perform these actions. Typically, these operations should be restricted so that only
 // the contract owner or users with specific permissions can execute them.
-//
-// Interest Rate Updates:
-// The contract lacks functionality to update interest rates.
-// In practical applications, it may be necessary to adjust the base interest rate based on
-// market conditions.
-//
-// Collateral Handling:
-// In the withdraw_collateral function, if a user attempts to
-// withdraw an amount of collateral exceeding the stored amount, the contract will return an error.
-// This behavior is expected, but the contract does not explicitly handle the situation where a
-// borrower has an outstanding loan. In real-world applications, borrowers should be prevented from
-// withdrawing collateral while having outstanding loans, or they should be required to repay first.
-//
-// Error Handling:
-// Some functions may require more detailed error messages when handling errors,
-// to aid in debugging and help users understand why an operation failed.
-//
-// Security:
-// The contract does not address security considerations,
-// such as integer overflow or re-entrancy attacks. Although CosmWasm has
-// certain security mechanisms in place, it is best to explicitly handle potential
-// security risks within the contract logic.
-//
-// Loan and Repayment Details:
-// The contract simplifies the loan and repayment process and does not account
-// for complex scenarios such as loan terms and overdue repayments.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::{attr, coins, from_json, ContractResult, OverflowError, OverflowOperation, OwnedDeps, ReplyOn, SubMsgResponse, SystemError, SystemResult, WasmQuery};
+    use cosmwasm_std::testing::{mock_dependencies, mock_dependencies_with_balance, mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+    use crate::helpers::{BLOCKS_PER_YEAR, SECONDS_PER_YEAR};
+
+    // dependencies whose contract account holds plenty of every denom the tests lend out
+    fn funded_deps() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        mock_dependencies_with_balance(&[coin(u128::MAX, "usdc"), coin(u128::MAX, "uatom")])
+    }
+
+    fn instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            owner: "owner".to_string(),
+            base_interest_rate: Decimal::percent(5),
+            max_ltv: Decimal::percent(75),
+            liquidation_threshold: Decimal::percent(80),
+            borrow_denom: "usdc".to_string(),
+            loan_term_seconds: 0,
+            penalty_rate: Decimal::zero(),
+            reserve_factor: Decimal::zero(),
+            slope: Decimal::zero(),
+            max_borrow_per_user: Uint128::zero(),
+            min_collateral_amount: Uint128::zero(),
+            treasury: "treasury".to_string(),
+            recovery_grace_seconds: 0,
+            origination_fee: Decimal::zero(),
+            dust_threshold: Uint128::zero(),
+            borrow_cooldown_seconds: 0,
+            compound: false,
+            liquidation_bonus: Decimal::percent(5),
+            grace_interest_free_seconds: 0,
+            price_max_age_seconds: 0,
+            min_loan_seconds: 0,
+            max_loan_seconds: 0,
+            close_factor: Decimal::zero(),
+            accrual_mode: AccrualMode::Time,
+            oracle: None,
+        }
+    }
+
+    // execute, then deliver a successful reply to every submessage that asks for one, as the chain would
+    fn exec(mut deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> Result<Response, ContractError> {
+        let res = execute(deps.branch(), env.clone(), info, msg)?;
+        for sub in res.messages.iter().filter(|sub| sub.reply_on != ReplyOn::Never) {
+            let result = SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None });
+            reply(deps.branch(), env.clone(), Reply { id: sub.id, result })?;
+        }
+        Ok(res)
+    }
+
+    fn setup(deps: DepsMut) {
+        setup_with(deps, instantiate_msg());
+    }
+
+    fn setup_with(mut deps: DepsMut, msg: InstantiateMsg) {
+        instantiate(deps.branch(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        for token in ["atom", "osmo"] {
+            let msg = ExecuteMsg::AllowToken { token_address: token.to_string() };
+            exec(deps.branch(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        }
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::one() };
+        exec(deps, mock_env(), mock_info("owner", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn instantiate_rejects_invalid_owner() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { owner: "X".to_string(), ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Std(StdError::GenericErr { .. })));
+        assert!(CONFIG.may_load(&deps.storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn instantiate_rejects_empty_owner() {
+        for owner in ["", "   ", "\t\n"] {
+            let mut deps = mock_dependencies();
+            let msg = InstantiateMsg { owner: owner.to_string(), ..instantiate_msg() };
+            let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+            assert_eq!(err, ContractError::EmptyOwner {});
+            assert!(CONFIG.may_load(&deps.storage).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn instantiate_accepts_valid_owner() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { owner: "alice".to_string(), ..instantiate_msg() };
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().owner, Addr::unchecked("alice"));
+    }
+
+    #[test]
+    fn instantiate_rejects_empty_borrow_denom() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { borrow_denom: " ".to_string(), ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDenom {});
+    }
+
+    #[test]
+    fn loans_in_different_denoms_are_isolated() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let mut env = mock_env();
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Borrow { denom: "uatom".to_string(), amount: Uint128::new(200), rate_mode: None, duration: None, request_id: None };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(200, "uatom") }, BORROW_REPLY_ID)]
+        );
+        // 同一幣別不可重複借款，但總額仍受 LTV 限制
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanAlreadyExists {});
+        let msg = ExecuteMsg::Borrow { denom: "uosmo".to_string(), amount: Uint128::new(251), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(750) });
+
+        // 各自計息：usdc 已累積一年利息，uatom 剛開立
+        let loan = |deps: Deps, denom: &str| -> LoanResponse {
+            let msg = QueryMsg::GetLoan { borrower: "alice".to_string(), denom: denom.to_string() };
+            from_json(query(deps, env.clone(), msg).unwrap()).unwrap()
+        };
+        assert_eq!(loan(deps.as_ref(), "usdc").total_due, Uint128::new(315));
+        assert_eq!(loan(deps.as_ref(), "uatom").total_due, Uint128::new(200));
+
+        // 以錯誤幣別還款會被拒絕
+        let msg = ExecuteMsg::RepayLoan { denom: "uatom".to_string(), amount: Uint128::new(315) };
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(315, "usdc")), msg).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch { denom: "uatom".to_string(), amount: Uint128::new(315) });
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(315) };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(315, "usdc")), msg).unwrap();
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(loan(deps.as_ref(), "uatom").total_due, Uint128::new(200));
+
+        let msg = ExecuteMsg::RepayLoan { denom: "uatom".to_string(), amount: Uint128::new(50) };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(50, "uatom")), msg).unwrap();
+        assert_eq!(loan(deps.as_ref(), "uatom").amount_borrowed, Uint128::new(150));
+
+        // 借款與利息總額依幣別分開記錄
+        let stats = |denom: &str| -> StatsResponse {
+            from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetStats { denom: Some(denom.to_string()) }).unwrap()).unwrap()
+        };
+        assert_eq!(stats("usdc").total_borrowed, Uint128::zero());
+        assert_eq!(stats("usdc").total_interest_collected, Uint128::new(15));
+        assert_eq!(stats("uatom").total_borrowed, Uint128::new(150));
+        assert_eq!(stats("uatom").total_interest_collected, Uint128::zero());
+    }
+
+    #[test]
+    fn borrow_without_contract_balance_is_rejected() {
+        let mut deps = mock_dependencies_with_balance(&coins(499, "usdc"));
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientLiquidity { available: Uint128::new(499) });
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+    }
+
+    #[test]
+    fn borrow_liquidity_covers_the_origination_fee() {
+        let borrow = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        // 990 would cover the payout but not the 10 fee sent on to the treasury
+        let mut deps = mock_dependencies_with_balance(&coins(999, "usdc"));
+        setup_with(deps.as_mut(), InstantiateMsg { origination_fee: Decimal::percent(1), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000);
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientLiquidity { available: Uint128::new(999) });
+
+        let mut deps = mock_dependencies_with_balance(&coins(1_000, "usdc"));
+        setup_with(deps.as_mut(), InstantiateMsg { origination_fee: Decimal::percent(1), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000);
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow).unwrap();
+    }
+
+    #[test]
+    fn borrow_reports_resulting_ltv() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let attr = |key: &str| res.attributes.iter().find(|a| a.key == key).unwrap().value.clone();
+        // 400 / 1_000，剩餘額度 1_000 * 0.75 - 400
+        assert_eq!(attr("ltv"), "0.4");
+        assert_eq!(attr("remaining_borrow_capacity"), "350");
+    }
+
+    fn capacity(deps: Deps, borrower: &str) -> Uint128 {
+        let msg = QueryMsg::BorrowCapacity { borrower: borrower.to_string() };
+        let res: BorrowCapacityResponse = from_json(query(deps, mock_env(), msg).unwrap()).unwrap();
+        res.capacity
+    }
+
+    #[test]
+    fn liquidatable_loans_lists_only_underwater_positions() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for (borrower, amount) in [("alice", 400), ("bob", 750), ("cat", 700)] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let liquidatable = |env: Env, limit: Option<u32>| -> Vec<String> {
+            let res: LiquidatableLoansResponse = from_json(query(deps.as_ref(), env, QueryMsg::LiquidatableLoans { limit }).unwrap()).unwrap();
+            res.loans.into_iter().map(|(borrower, _, _)| borrower).collect()
+        };
+        assert!(liquidatable(mock_env(), None).is_empty());
+
+        // two years at 5%: bob owes 825 and cat 770 against a threshold of 800, alice stays healthy
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 2);
+        assert_eq!(liquidatable(env.clone(), None), vec!["bob"]);
+
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 2);
+        assert_eq!(liquidatable(env.clone(), None), vec!["bob", "cat"]);
+        assert_eq!(liquidatable(env, Some(1)), vec!["bob"]);
+    }
+
+    #[test]
+    fn solvency_check_aggregates_all_positions() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        // alice is comfortably collateralized, bob is close to the edge
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 1_000);
+        for (borrower, amount) in [("alice", 100), ("bob", 750)] {
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+        let solvency = |deps: Deps, env: Env| -> SolvencyResponse { from_json(query(deps, env, QueryMsg::SolvencyCheck {}).unwrap()).unwrap() };
+
+        let res = solvency(deps.as_ref(), mock_env());
+        assert_eq!(res, SolvencyResponse { solvent: true, total_collateral_value: Uint128::new(2_000), total_outstanding_debt: Uint128::new(850) });
+
+        // bob is underwater after two years, but alice's surplus keeps the protocol solvent overall
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 2);
+        assert!(health(deps.as_ref(), env.clone(), "bob") < Decimal::one());
+        let res = solvency(deps.as_ref(), env.clone());
+        assert!(res.solvent);
+        assert_eq!(res.total_outstanding_debt, Uint128::new(935));
+
+        // far enough out the aggregate debt exceeds 2_000 * 0.8
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 20);
+        let res = solvency(deps.as_ref(), env);
+        assert!(!res.solvent);
+        assert_eq!(res.total_outstanding_debt, Uint128::new(1_785));
+
+        // collateral counts at its weighted value: at a 50% factor 2_000 atom back only 800 of the 850 owed
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let res = solvency(deps.as_ref(), mock_env());
+        assert_eq!(res, SolvencyResponse { solvent: false, total_collateral_value: Uint128::new(1_000), total_outstanding_debt: Uint128::new(850) });
+    }
+
+    fn withdrawable(deps: Deps, env: Env, borrower: &str) -> Uint128 {
+        let msg = QueryMsg::WithdrawableCollateral { borrower: borrower.to_string(), token_address: "atom".to_string() };
+        let res: WithdrawableCollateralResponse = from_json(query(deps, env, msg).unwrap()).unwrap();
+        res.amount
+    }
+
+    #[test]
+    fn withdrawable_collateral_accounts_for_loans() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::new(1_000));
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "bob"), Uint128::zero());
+
+        // 400 of debt needs ceil(400 / 0.75) = 534 of collateral at a 75% ltv
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::new(466));
+        withdraw(deps.as_mut(), "alice", 467).unwrap_err();
+        withdraw(deps.as_mut(), "alice", 466).unwrap();
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::zero());
+    }
+
+    #[test]
+    fn withdrawable_collateral_is_zero_for_maxed_position() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(700), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        // ceil(700 / 0.75) = 934
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::new(66));
+
+        // after two years the debt of 770 exceeds what the collateral backs
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 2);
+        assert_eq!(withdrawable(deps.as_ref(), env, "alice"), Uint128::zero());
+    }
+
+    #[test]
+    fn borrow_capacity_tracks_position() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        assert_eq!(capacity(deps.as_ref(), "alice"), Uint128::zero());
+
+        deposit(deps.as_mut(), "alice", 1_000);
+        assert_eq!(capacity(deps.as_ref(), "alice"), Uint128::new(750));
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(capacity(deps.as_ref(), "alice"), Uint128::new(450));
+    }
+
+    #[test]
+    fn borrow_cooldown_blocks_rapid_reborrow() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { borrow_cooldown_seconds: 60, ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let mut env = mock_env();
+        let start = env.block.time.seconds();
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg.clone()).unwrap();
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(500) };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(500, "usdc")), repay).unwrap();
+
+        // 還清後立即再借仍在冷卻期內
+        env.block.time = env.block.time.plus_seconds(59);
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::BorrowCooldown { ready_at: start + 60 });
+
+        env.block.time = env.block.time.plus_seconds(1);
+        exec(deps.as_mut(), env, mock_info("alice", &[]), msg).unwrap();
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+    }
+
+    #[test]
+    fn zero_cooldown_allows_immediate_reborrow() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap();
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(500) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(500, "usdc")), repay).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn borrow_rejects_empty_denom() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDenom {});
+    }
+
+    #[test]
+    fn query_config_after_instantiate() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_json(res).unwrap();
+        assert_eq!(config.owner, "owner");
+        assert_eq!(config.base_interest_rate, Decimal::percent(5));
+    }
+
+    #[test]
+    fn query_config_round_trips_every_field() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            loan_term_seconds: 86_400,
+            penalty_rate: Decimal::percent(12),
+            reserve_factor: Decimal::percent(10),
+            slope: Decimal::percent(20),
+            max_borrow_per_user: Uint128::new(5_000),
+            min_collateral_amount: Uint128::new(10),
+            recovery_grace_seconds: 3_600,
+            origination_fee: Decimal::permille(5),
+            dust_threshold: Uint128::new(3),
+            borrow_cooldown_seconds: 60,
+            grace_interest_free_seconds: 600,
+            price_max_age_seconds: 300,
+            min_loan_seconds: 3_600,
+            max_loan_seconds: 172_800,
+            close_factor: Decimal::percent(50),
+            accrual_mode: AccrualMode::BlockHeight,
+            oracle: Some("oracle".to_string()),
+            ..instantiate_msg()
+        };
+        setup_with(deps.as_mut(), msg);
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_json(res).unwrap();
+        assert_eq!(
+            config,
+            ConfigResponse {
+                owner: Addr::unchecked("owner"),
+                base_interest_rate: Decimal::percent(5),
+                max_ltv: Decimal::percent(75),
+                liquidation_threshold: Decimal::percent(80),
+                paused: false,
+                pending_owner: None,
+                borrow_denom: "usdc".to_string(),
+                loan_term_seconds: 86_400,
+                penalty_rate: Decimal::percent(12),
+                reserve_factor: Decimal::percent(10),
+                slope: Decimal::percent(20),
+                max_borrow_per_user: Uint128::new(5_000),
+                min_collateral_amount: Uint128::new(10),
+                treasury: Addr::unchecked("treasury"),
+                recovery_grace_seconds: 3_600,
+                origination_fee: Decimal::permille(5),
+                dust_threshold: Uint128::new(3),
+                borrow_cooldown_seconds: 60,
+                compound: false,
+                liquidation_bonus: Decimal::percent(5),
+                grace_interest_free_seconds: 600,
+                price_max_age_seconds: 300,
+                min_loan_seconds: 3_600,
+                max_loan_seconds: 172_800,
+                close_factor: Decimal::percent(50),
+                accrual_mode: AccrualMode::BlockHeight,
+                oracle: Some(Addr::unchecked("oracle")),
+            }
+        );
+    }
+
+    #[test]
+    fn query_loan_reports_live_debt() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 5% a year for a quarter of a year
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 4);
+        let res = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(1_000_000));
+        assert_eq!(loan.accrued_interest, Uint128::new(12_500));
+        assert_eq!(loan.total_due, Uint128::new(1_012_500));
+    }
+
+    #[test]
+    fn owner_queries() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let res: OwnerResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetOwner {}).unwrap()).unwrap();
+        assert_eq!(res.owner, "owner");
+
+        let msg = QueryMsg::IsOwner { address: "owner".to_string() };
+        let res: IsOwnerResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert!(res.is_owner);
+
+        let msg = QueryMsg::IsOwner { address: "alice".to_string() };
+        let res: IsOwnerResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert!(!res.is_owner);
+
+        let msg = QueryMsg::IsOwner { address: "Owner".to_string() };
+        let err = query(deps.as_ref(), mock_env(), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Std(StdError::GenericErr { .. })));
+    }
+
+    #[test]
+    fn instantiate_validates_interest_rate() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { base_interest_rate: Decimal::percent(101), ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidInterestRate { max: Decimal::percent(100) });
+
+        let msg = InstantiateMsg { base_interest_rate: Decimal::percent(100), ..instantiate_msg() };
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn update_interest_rate_validates_rate() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(101) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidInterestRate { max: Decimal::percent(100) });
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(100) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().base_interest_rate, Decimal::percent(100));
+    }
+
+    #[test]
+    fn rate_change_affects_floating_but_not_fixed_loans() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: Some(RateMode::Fixed), duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: Some(RateMode::Floating), duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(10) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let loan = |deps: Deps, borrower: &str| -> LoanResponse {
+            let msg = QueryMsg::GetLoan { borrower: borrower.to_string(), denom: "usdc".to_string() };
+            from_json(query(deps, env.clone(), msg).unwrap()).unwrap()
+        };
+        // the fixed loan keeps its 5% snapshot, the floating one accrues at the new 10%
+        assert_eq!(loan(deps.as_ref(), "alice").total_due, Uint128::new(420));
+        let floating = loan(deps.as_ref(), "bob");
+        assert_eq!(floating.total_due, Uint128::new(440));
+        assert_eq!(floating.rate_mode, RateMode::Floating);
+    }
+
+    #[test]
+    fn rate_changes_are_recorded_in_history() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let first = mock_env();
+        let mut second = mock_env();
+        second.block.time = second.block.time.plus_seconds(3_600);
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(7) };
+        exec(deps.as_mut(), first.clone(), mock_info("owner", &[]), msg).unwrap();
+        sudo(deps.as_mut(), second.clone(), SudoMsg::SetBaseRate { rate: Decimal::percent(9) }).unwrap();
+
+        // a second change in the same block replaces that block's entry
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(8) };
+        exec(deps.as_mut(), second.clone(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().base_interest_rate, Decimal::percent(8));
+
+        let msg = QueryMsg::RateHistory { start_after: None, limit: None };
+        let res: RateHistoryResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(
+            res.history,
+            vec![(first.block.time.seconds(), Decimal::percent(7)), (second.block.time.seconds(), Decimal::percent(8))]
+        );
+
+        let msg = QueryMsg::RateHistory { start_after: Some(first.block.time.seconds()), limit: None };
+        let res: RateHistoryResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.history, vec![(second.block.time.seconds(), Decimal::percent(8))]);
+    }
+
+    #[test]
+    fn sudo_sets_base_rate() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        sudo(deps.as_mut(), mock_env(), SudoMsg::SetBaseRate { rate: Decimal::percent(8) }).unwrap();
+        let config: ConfigResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap()).unwrap();
+        assert_eq!(config.base_interest_rate, Decimal::percent(8));
+    }
+
+    #[test]
+    fn query_loan_and_collateral() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+
+        deposit(deps.as_mut(), "alice", 200);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(100), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(100));
+        assert_eq!(loan.loan_start_time, mock_env().block.time.seconds());
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(
+            collateral.collaterals,
+            vec![Collateral { token_address: "atom".to_string(), amount: Uint128::new(200), cw20: false, deposited_at: mock_env().block.time.seconds() }]
+        );
+    }
+
+    #[test]
+    fn query_missing_loan_returns_error() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let err = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "bob".to_string(), denom: "usdc".to_string() }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+    }
+
+    fn deposit(deps: DepsMut, sender: &str, amount: u128) {
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(amount) };
+        exec(deps, mock_env(), mock_info(sender, &coins(amount, "atom")), msg).unwrap();
+    }
+
+    fn withdraw(deps: DepsMut, sender: &str, amount: u128) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::WithdrawCollateral { token_address: "atom".to_string(), amount: Uint128::new(amount) };
+        exec(deps, mock_env(), mock_info(sender, &[]), msg)
+    }
+
+    #[test]
+    fn withdraw_without_loan_is_allowed() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+
+        withdraw(deps.as_mut(), "alice", 100).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert!(collateral.collaterals.is_empty());
+    }
+
+    #[test]
+    fn withdraw_returns_collateral_to_sender() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+
+        let res = withdraw(deps.as_mut(), "alice", 40).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(40, "atom") }, WITHDRAW_REPLY_ID)]
+        );
+    }
+
+    #[test]
+    fn withdraw_by_percentage() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 101);
+        let withdraw_pct = |deps: DepsMut, pct: Decimal| {
+            let msg = ExecuteMsg::WithdrawCollateralPct { token_address: "atom".to_string(), pct };
+            exec(deps, mock_env(), mock_info("alice", &[]), msg)
+        };
+
+        let err = withdraw_pct(deps.as_mut(), Decimal::percent(101)).unwrap_err();
+        assert_eq!(err, ContractError::InvalidPercentage {});
+
+        // half of 101, rounded down
+        let res = withdraw_pct(deps.as_mut(), Decimal::percent(50)).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(50, "atom") }, WITHDRAW_REPLY_ID)]
+        );
+
+        let res = withdraw_pct(deps.as_mut(), Decimal::percent(100)).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(51, "atom") }, WITHDRAW_REPLY_ID)]
+        );
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert!(collateral.collaterals.is_empty());
+    }
+
+    #[test]
+    fn withdraw_percentage_keeps_loan_backing_checks() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(60), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::WithdrawCollateralPct { token_address: "atom".to_string(), pct: Decimal::percent(50) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        // the remaining 50 only backs 37 at a 75% ltv
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(37), borrowed: Uint128::new(60) });
+    }
+
+    #[test]
+    fn withdraw_excess_with_loan_is_allowed() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(60), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 80 left backs exactly the 60 borrowed at a 75% ltv
+        let err = withdraw(deps.as_mut(), "alice", 21).unwrap_err();
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(59), borrowed: Uint128::new(60) });
+        withdraw(deps.as_mut(), "alice", 20).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(80));
+    }
+
+    #[test]
+    fn withdraw_respects_collateral_factor() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(375), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 1_000 at a 50% factor and 75% ltv backs exactly the 375 borrowed, so nothing can come out
+        let err = withdraw(deps.as_mut(), "alice", 625).unwrap_err();
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(140), borrowed: Uint128::new(375) });
+        let err = withdraw(deps.as_mut(), "alice", 1).unwrap_err();
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(374), borrowed: Uint128::new(375) });
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::zero());
+    }
+
+    #[test]
+    fn withdrawable_collateral_is_priced_and_weighted() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 300 of debt needs 400 of value at a 75% ltv, which 400 atom at 2.0 and a 50% factor provide
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::new(600));
+        withdraw(deps.as_mut(), "alice", 601).unwrap_err();
+        withdraw(deps.as_mut(), "alice", 600).unwrap();
+        assert_eq!(withdrawable(deps.as_ref(), mock_env(), "alice"), Uint128::zero());
+    }
+
+    #[test]
+    fn withdraw_all_with_loan_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(60), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let err = withdraw(deps.as_mut(), "alice", 100).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::CollateralLocked { remaining: Uint128::zero(), borrowed: Uint128::new(60) }
+        );
+    }
+
+    #[test]
+    fn deposit_records_block_time() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let deposited_at = |deps: Deps| -> u64 {
+            let res: CollateralResponse = from_json(query(deps, mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap()).unwrap();
+            res.collaterals[0].deposited_at
+        };
+
+        deposit(deps.as_mut(), "alice", 100);
+        assert_eq!(deposited_at(deps.as_ref()), mock_env().block.time.seconds());
+
+        // topping up moves the timestamp to the latest deposit
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(3_600);
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(50) };
+        exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(50, "atom")), msg).unwrap();
+        assert_eq!(deposited_at(deps.as_ref()), env.block.time.seconds());
+    }
+
+    #[test]
+    fn repeated_deposits_accumulate_per_token() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        for (token, amount) in [("atom", 100), ("osmo", 50), ("atom", 20), ("atom", 30)] {
+            let msg = ExecuteMsg::DepositCollateral { token_address: token.to_string(), amount: Uint128::new(amount) };
+            exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(amount, token)), msg).unwrap();
+        }
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(
+            collateral.collaterals,
+            vec![
+                Collateral { token_address: "atom".to_string(), amount: Uint128::new(150), cw20: false, deposited_at: mock_env().block.time.seconds() },
+                Collateral { token_address: "osmo".to_string(), amount: Uint128::new(50), cw20: false, deposited_at: mock_env().block.time.seconds() },
+            ]
+        );
+    }
+
+    #[test]
+    fn deposit_reports_new_token_total() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let mut new_total = String::new();
+        for amount in [100, 20] {
+            let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(amount) };
+            let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(amount, "atom")), msg).unwrap();
+            new_total = res.attributes.iter().find(|a| a.key == "new_total").unwrap().value.clone();
+        }
+        assert_eq!(new_total, "120");
+    }
+
+    #[test]
+    fn collateral_by_token_sums_across_users() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for (owner, token, amount) in [("alice", "atom", 100), ("bob", "atom", 250), ("alice", "osmo", 40), ("cat", "osmo", 60)] {
+            let msg = ExecuteMsg::DepositCollateral { token_address: token.to_string(), amount: Uint128::new(amount) };
+            exec(deps.as_mut(), mock_env(), mock_info(owner, &coins(amount, token)), msg).unwrap();
+        }
+        withdraw(deps.as_mut(), "bob", 50).unwrap();
+
+        let totals = |deps: Deps| -> Vec<(String, Uint128)> {
+            let res: CollateralByTokenResponse = from_json(query(deps, mock_env(), QueryMsg::CollateralByToken {}).unwrap()).unwrap();
+            res.totals
+        };
+        assert_eq!(totals(deps.as_ref()), [("atom".to_string(), Uint128::new(300)), ("osmo".to_string(), Uint128::new(100))]);
+
+        // tokens nobody holds any more drop out
+        let msg = ExecuteMsg::WithdrawCollateral { token_address: "osmo".to_string(), amount: Uint128::new(40) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::WithdrawCollateral { token_address: "osmo".to_string(), amount: Uint128::new(60) };
+        exec(deps.as_mut(), mock_env(), mock_info("cat", &[]), msg).unwrap();
+        assert_eq!(totals(deps.as_ref()), [("atom".to_string(), Uint128::new(300))]);
+    }
+
+    #[test]
+    fn update_interest_rate_requires_owner() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(8) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn owner_can_update_interest_rate() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(8) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_json(res).unwrap();
+        assert_eq!(config.base_interest_rate, Decimal::percent(8));
+    }
+
+    #[test]
+    fn update_config_changes_only_given_fields() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let before = CONFIG.load(&deps.storage).unwrap();
+
+        let update = UpdateConfigMsg {
+            max_ltv: Some(Decimal::percent(60)),
+            dust_threshold: Some(Uint128::new(25)),
+            ..UpdateConfigMsg::default()
+        };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::UpdateConfig(Box::new(update))).unwrap();
+        assert_eq!(res.attributes.len(), 3);
+
+        let after = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(after, Config { max_ltv: Decimal::percent(60), dust_threshold: Uint128::new(25), ..before });
+    }
+
+    #[test]
+    fn update_config_validates_input() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg { slope: Some(Decimal::percent(10)), ..UpdateConfigMsg::default() }));
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let msg = ExecuteMsg::UpdateConfig(Box::default());
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::EmptyConfigUpdate {});
+
+        let msg = ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg { reserve_factor: Some(Decimal::percent(101)), ..UpdateConfigMsg::default() }));
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidReserveFactor {});
+    }
+
+    #[test]
+    fn zero_deposit_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::zero() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::ZeroAmount {});
+    }
+
+    #[test]
+    fn deposit_requires_matching_funds() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(100) };
+        let expected = ContractError::FundsMismatch { denom: "atom".to_string(), amount: Uint128::new(100) };
+
+        // no funds
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, expected);
+        // wrong denom
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "osmo")), msg.clone()).unwrap_err();
+        assert_eq!(err, expected);
+        // mismatched amount
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(99, "atom")), msg.clone()).unwrap_err();
+        assert_eq!(err, expected);
+
+        // correct funds
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "atom")), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(100));
+    }
+
+    #[test]
+    fn swap_collateral_keeping_ltv_is_allowed() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "osmo".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 500 atom + 1_000 osmo at 50% is still worth 1_000, enough for 300 at 75%
+        let msg = ExecuteMsg::SwapCollateral {
+            from_token: "atom".to_string(),
+            to_token: "osmo".to_string(),
+            from_amount: Uint128::new(500),
+            to_amount: Uint128::new(1_000),
+        };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1_000, "osmo")), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(500, "atom") }, RELEASE_LOCK_REPLY_ID)]
+        );
+        let alice = Addr::unchecked("alice");
+        assert_eq!(COLLATERALS.load(&deps.storage, (&alice, "atom")).unwrap().amount, Uint128::new(500));
+        assert_eq!(COLLATERALS.load(&deps.storage, (&alice, "osmo")).unwrap().amount, Uint128::new(1_000));
+        assert_eq!(stats(deps.as_ref()).total_collateral, Uint128::new(1_500));
+    }
+
+    #[test]
+    fn swap_collateral_breaching_ltv_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "osmo".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(600), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // swapping all atom for the same amount of osmo halves the collateral value
+        let msg = ExecuteMsg::SwapCollateral {
+            from_token: "atom".to_string(),
+            to_token: "osmo".to_string(),
+            from_amount: Uint128::new(1_000),
+            to_amount: Uint128::new(1_000),
+        };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1_000, "osmo")), msg).unwrap_err();
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(375), borrowed: Uint128::new(600) });
+        assert_eq!(COLLATERALS.load(&deps.storage, (&Addr::unchecked("alice"), "atom")).unwrap().amount, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn swap_collateral_must_cover_accrued_interest() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "osmo".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(370), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // the 370 principal fits under the 375 the osmo would back, but a year of interest does not
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::SwapCollateral {
+            from_token: "atom".to_string(),
+            to_token: "osmo".to_string(),
+            from_amount: Uint128::new(1_000),
+            to_amount: Uint128::new(1_000),
+        };
+        let err = exec(deps.as_mut(), env, mock_info("alice", &coins(1_000, "osmo")), msg).unwrap_err();
+        assert_eq!(err, ContractError::CollateralLocked { remaining: Uint128::new(375), borrowed: Uint128::new(389) });
+    }
+
+    #[test]
+    fn swap_collateral_enforces_min_collateral_on_the_new_position() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { min_collateral_amount: Uint128::new(100), ..instantiate_msg() });
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "osmo".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let swap = |to_amount: u128| ExecuteMsg::SwapCollateral {
+            from_token: "atom".to_string(),
+            to_token: "osmo".to_string(),
+            from_amount: Uint128::new(50),
+            to_amount: Uint128::new(to_amount),
+        };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(99, "osmo")), swap(99)).unwrap_err();
+        assert_eq!(err, ContractError::DepositBelowMinimum { min: Uint128::new(100) });
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "osmo")), swap(100)).unwrap();
+        // topping up an existing position only needs the resulting position to meet the minimum
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(10, "osmo")), swap(10)).unwrap();
+        assert_eq!(COLLATERALS.load(&deps.storage, (&Addr::unchecked("alice"), "osmo")).unwrap().amount, Uint128::new(110));
+    }
+
+    #[test]
+    fn withdraw_more_than_deposited_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 100);
+
+        let err = withdraw(deps.as_mut(), "alice", 101).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientCollateral {});
+    }
+
+    #[test]
+    fn withdraw_without_collateral_reports_no_collateral() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let err = withdraw(deps.as_mut(), "bob", 1).unwrap_err();
+        assert_eq!(err, ContractError::NoCollateral {});
+    }
+
+    #[test]
+    fn repay_without_loan_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(100) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "usdc")), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+    }
+
+    #[test]
+    fn partial_repayment_reduces_balance() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // after one year 1_050_000 is due; pay half of it
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(525_000) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(525_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(50_000));
+
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(525_000));
+        assert_eq!(loan.loan_start_time, env.block.time.seconds());
+
+        // half a year later 525_000 + 13_125 is due; paying it clears the loan
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(538_125) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(538_125, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(13_125));
+
+        let err = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+    }
+
+    #[test]
+    fn block_mode_accrues_by_block_height() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { accrual_mode: AccrualMode::BlockHeight, ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let get_loan = |env: Env| -> LoanResponse {
+            let res = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+            from_json(res).unwrap()
+        };
+        assert_eq!(get_loan(mock_env()).loan_start_height, mock_env().block.height);
+
+        // block time passing without new blocks accrues nothing
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        assert_eq!(get_loan(env.clone()).accrued_interest, Uint128::zero());
+
+        // half a year of blocks accrues half a year of interest at 5%
+        env.block.height += BLOCKS_PER_YEAR / 2;
+        assert_eq!(get_loan(env).accrued_interest, Uint128::new(25_000));
+    }
+
+    #[test]
+    fn zero_rate_loan_is_cleared_by_its_principal() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { base_interest_rate: Decimal::zero(), reserve_factor: Decimal::percent(10), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.interest_rate, Decimal::zero());
+        assert_eq!(loan.total_due, Uint128::new(1_000));
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(1_000) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(1_000, "usdc")), msg).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(interest_paid(&res), Uint128::zero());
+        let reserve_cut = res.attributes.iter().find(|a| a.key == "reserve_cut").unwrap();
+        assert_eq!(reserve_cut.value, "0");
+        assert_eq!(PROTOCOL_RESERVES.may_load(&deps.storage, "usdc").unwrap().unwrap_or_default(), Uint128::zero());
+
+        let err = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+    }
+
+    #[test]
+    fn partial_repayment_reports_receipt() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { reserve_factor: Decimal::percent(10), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 1_050_000 due after a year; 300_000 covers the 50_000 interest and 250_000 principal
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(300_000) };
+        let res = exec(deps.as_mut(), env, mock_info("alice", &coins(300_000, "usdc")), msg).unwrap();
+
+        let receipt = |key: &str| -> u128 { res.attributes.iter().find(|a| a.key == key).unwrap().value.parse().unwrap() };
+        assert_eq!(receipt("principal_paid"), 250_000);
+        assert_eq!(receipt("interest_paid"), 50_000);
+        assert_eq!(receipt("reserve_cut"), 5_000);
+        assert_eq!(receipt("remaining_balance"), 750_000);
+    }
+
+    #[test]
+    fn borrow_snapshots_token_rate_override() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetTokenRate { token_address: "atom".to_string(), rate: Some(Decimal::percent(3)) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        // the quote for alice applies the override on the atom collateral, the generic one does not
+        let quote = |borrower: Option<&str>| -> Decimal {
+            let msg = QueryMsg::CurrentBorrowRate { borrower: borrower.map(str::to_string), denom: None };
+            from_json::<BorrowRateResponse>(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap().rate
+        };
+        assert_eq!((quote(Some("alice")), quote(None)), (Decimal::percent(3), Decimal::percent(5)));
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // clearing the override afterwards does not touch the open loan
+        let msg = ExecuteMsg::SetTokenRate { token_address: "atom".to_string(), rate: None };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.interest_rate, Decimal::percent(3));
+    }
+
+    #[test]
+    fn token_rate_override_is_owner_only() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetTokenRate { token_address: "atom".to_string(), rate: Some(Decimal::percent(3)) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let msg = ExecuteMsg::SetTokenRate { token_address: "atom".to_string(), rate: Some(Decimal::percent(101)) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidInterestRate { max: MAX_INTEREST_RATE });
+    }
+
+    #[test]
+    fn borrow_snapshots_configured_base_rate() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { base_interest_rate: Decimal::percent(9), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // later rate changes do not touch the open loan
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(12) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.interest_rate, Decimal::percent(9));
+    }
+
+    #[test]
+    fn origination_fee_is_withheld_from_payout() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { origination_fee: Decimal::percent(1), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 2_000_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(990_000, "usdc") }, BORROW_REPLY_ID)]
+        );
+
+        // once the payout succeeds the fee goes to the treasury, not the reserves
+        let result = SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None });
+        let res = reply(deps.as_mut(), mock_env(), Reply { id: BORROW_REPLY_ID, result }).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(10_000, "usdc") })]
+        );
+        assert_eq!(PROTOCOL_RESERVES.may_load(&deps.storage, "usdc").unwrap().unwrap_or_default(), Uint128::zero());
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let res = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.total_due, Uint128::new(1_050_000));
+    }
+
+    #[test]
+    fn origination_fee_above_one_is_rejected() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { origination_fee: Decimal::percent(101), ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidOriginationFee {});
+
+        setup(deps.as_mut());
+        let update = |fee: Decimal| ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg { origination_fee: Some(fee), ..UpdateConfigMsg::default() }));
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), update(Decimal::percent(101))).unwrap_err();
+        assert_eq!(err, ContractError::InvalidOriginationFee {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), update(Decimal::one())).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().origination_fee, Decimal::one());
+    }
+
+    #[test]
+    fn simulate_borrow_matches_actual_borrow() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { slope: Decimal::percent(20), ..instantiate_msg() });
+        TOTAL_LIQUIDITY.save(&mut deps.storage, "usdc", &Uint128::new(1_000)).unwrap();
+        deposit(deps.as_mut(), "bob", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let simulate = |deps: Deps, amount: u128| -> SimulateBorrowResponse {
+            let msg = QueryMsg::SimulateBorrow { borrower: "alice".to_string(), denom: "usdc".to_string(), amount: Uint128::new(amount) };
+            from_json(query(deps, mock_env(), msg).unwrap()).unwrap()
+        };
+
+        let sim = simulate(deps.as_ref(), 751);
+        assert!(!sim.allowed);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(751), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(sim.error, Some(err.to_string()));
+
+        let sim = simulate(deps.as_ref(), 400);
+        assert!(sim.allowed);
+        assert_eq!(sim.error, None);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let loan = LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap();
+        assert_eq!(sim.interest_rate, loan.interest_rate);
+        assert_eq!(sim.interest_rate, Decimal::percent(15));
+        assert_eq!(sim.health_factor, health(deps.as_ref(), mock_env(), "alice"));
+    }
+
+    #[test]
+    fn borrow_at_ltv_limit_is_allowed() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn borrow_over_ltv_limit_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(751), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(750) });
+    }
+
+    #[test]
+    fn borrow_without_collateral_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::NoCollateral {});
+    }
+
+    #[test]
+    fn borrow_respects_per_user_cap() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { max_borrow_per_user: Uint128::new(500), ..instantiate_msg() });
+        for (borrower, amount) in [("amy", 499), ("ben", 500)] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        deposit(deps.as_mut(), "cat", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(501), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("cat", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::BorrowLimitExceeded { limit: Uint128::new(500) });
+    }
+
+    #[test]
+    fn zero_per_user_cap_is_unlimited() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn second_borrow_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(100), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), env, mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanAlreadyExists {});
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(300));
+        assert_eq!(loan.loan_start_time, mock_env().block.time.seconds());
+    }
+
+    #[test]
+    fn liquidating_healthy_loan_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("bob", &coins(750, "usdc")), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanHealthy {});
+    }
+
+    #[test]
+    fn liquidation_eligibility_uses_the_health_factor() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // at a 50% factor the 1_000 raw collateral only backs 400 of the 750 debt
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::percent(50) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert!(health(deps.as_ref(), mock_env(), "alice") < Decimal::one());
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::LiquidatableLoans { limit: None }).unwrap();
+        let listed: LiquidatableLoansResponse = from_json(res).unwrap();
+        assert_eq!(listed.loans.len(), 1);
+
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("bob", &coins(750, "usdc")), msg).unwrap();
+        let repaid = res.attributes.iter().find(|a| a.key == "debt_repaid").unwrap();
+        assert_eq!(repaid.value, "750");
+    }
+
+    #[test]
+    fn liquidating_underwater_loan_pays_bonus() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // after two years the debt is 825, above 80% of the 1_000 collateral
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(800, "usdc")), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientFunds { denom: "usdc".to_string(), amount: Uint128::new(825) });
+
+        let res = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(825, "usdc")), msg).unwrap();
+        // 825 repaid plus a 5% bonus of 41
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "bob".to_string(), amount: coins(866, "atom") }, RELEASE_LOCK_REPLY_ID)]
+        );
+        let bonus = res.attributes.iter().find(|a| a.key == "bonus").unwrap();
+        assert_eq!(bonus.value, "41");
+
+        let err = query(deps.as_ref(), env.clone(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+        let res = query(deps.as_ref(), env, QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(134));
+    }
+
+    #[test]
+    fn batch_liquidation_sweeps_only_underwater_loans() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for (borrower, denom, amount) in [("alice", "usdc", 400), ("bob", "usdc", 750), ("cat", "usdc", 700), ("eve", "uatom", 700)] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: denom.to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        // four years at 5%: bob owes 900, cat 840 and eve 840 against a threshold of 800, alice's 480 stays healthy
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 4);
+        let msg = ExecuteMsg::BatchLiquidate { limit: Some(100) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("dan", &coins(2_000, "usdc")), msg).unwrap();
+        // each seizure covers the repayment plus a 5% bonus, and the unused 260 usdc goes back
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::new(BankMsg::Send { to_address: "dan".to_string(), amount: coins(945, "atom") }),
+                SubMsg::new(BankMsg::Send { to_address: "dan".to_string(), amount: coins(882, "atom") }),
+                SubMsg::reply_always(BankMsg::Send { to_address: "dan".to_string(), amount: coins(260, "usdc") }, RELEASE_LOCK_REPLY_ID),
+            ]
+        );
+        let liquidated = res.attributes.iter().find(|a| a.key == "liquidated").unwrap();
+        assert_eq!(liquidated.value, "2");
+        let skipped = res.attributes.iter().find(|a| a.key == "skipped").unwrap();
+        assert_eq!(skipped.value, "1");
+        let borrowers = |ty: &str| -> Vec<String> {
+            res.events.iter().filter(|e| e.ty == ty).map(|e| e.attributes.iter().find(|a| a.key == "borrower").unwrap().value.clone()).collect()
+        };
+        assert_eq!(borrowers("loan_liquidated"), vec!["bob", "cat"]);
+        // no uatom was attached for eve's loan, which is reported instead of failing the batch
+        assert_eq!(borrowers("liquidation_skipped"), vec!["eve"]);
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("eve"), "uatom")));
+
+        for borrower in ["bob", "cat"] {
+            let err = query(deps.as_ref(), env.clone(), QueryMsg::GetLoan { borrower: borrower.to_string(), denom: "usdc".to_string() }).unwrap_err();
+            assert_eq!(err, ContractError::NoActiveLoan {});
+        }
+        let res = query(deps.as_ref(), env, QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() });
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn loan_query_reports_time_until_due() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { loan_term_seconds: SECONDS_PER_YEAR, ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let loan = |deps: Deps, elapsed: u64| -> LoanResponse {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(elapsed);
+            let msg = QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() };
+            from_json(query(deps, env, msg).unwrap()).unwrap()
+        };
+
+        let fresh = loan(deps.as_ref(), 0);
+        assert_eq!((fresh.is_overdue, fresh.seconds_until_due), (false, Some(SECONDS_PER_YEAR)));
+        let almost = loan(deps.as_ref(), SECONDS_PER_YEAR - 1);
+        assert_eq!((almost.is_overdue, almost.seconds_until_due), (false, Some(1)));
+        let due = loan(deps.as_ref(), SECONDS_PER_YEAR);
+        assert_eq!((due.is_overdue, due.seconds_until_due), (false, Some(0)));
+        let overdue = loan(deps.as_ref(), SECONDS_PER_YEAR + 1);
+        assert_eq!((overdue.is_overdue, overdue.seconds_until_due), (true, Some(0)));
+    }
+
+    #[test]
+    fn partial_repayment_does_not_restart_the_term() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { loan_term_seconds: 100, ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let after = |elapsed: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(elapsed);
+            env
+        };
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::one() };
+        exec(deps.as_mut(), after(99), mock_info("alice", &coins(1, "usdc")), msg).unwrap();
+        let msg = QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let loan: LoanResponse = from_json(query(deps.as_ref(), after(99), msg.clone()).unwrap()).unwrap();
+        assert_eq!((loan.loan_start_time, loan.seconds_until_due), (after(99).block.time.seconds(), Some(1)));
+        let loan: LoanResponse = from_json(query(deps.as_ref(), after(197), msg).unwrap()).unwrap();
+        assert_eq!((loan.is_overdue, loan.seconds_until_due), (true, Some(0)));
+    }
+
+    #[test]
+    fn repeated_borrow_request_id_is_a_no_op() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let borrow = |denom: &str, request_id: &str| ExecuteMsg::Borrow {
+            denom: denom.to_string(),
+            amount: Uint128::new(100),
+            rate_mode: None,
+            duration: None,
+            request_id: Some(request_id.to_string()),
+        };
+
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", "req-1")).unwrap();
+        // a retry with the same id, even after the loan is repaid, opens nothing and pays nothing
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(100) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "usdc")), repay).unwrap();
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", "req-1")).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(res.attributes.iter().find(|a| a.key == "duplicate").unwrap().value, "true");
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::zero());
+
+        // a distinct id is processed
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", "req-2")).unwrap();
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::new(100));
+    }
+
+    #[test]
+    fn rolled_back_borrow_request_id_can_be_retried() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow {
+            denom: "usdc".to_string(),
+            amount: Uint128::new(100),
+            rate_mode: None,
+            duration: None,
+            request_id: Some("req-1".to_string()),
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap();
+        let result = SubMsgResult::Err("insufficient funds".to_string());
+        reply(deps.as_mut(), mock_env(), Reply { id: res.messages[0].id, result }).unwrap();
+
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+    }
+
+    #[test]
+    fn borrow_duration_must_be_within_bounds() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { min_loan_seconds: 86_400, max_loan_seconds: SECONDS_PER_YEAR, ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        deposit(deps.as_mut(), "alice", 1_000);
+        let borrow = |denom: &str, duration: Option<u64>| ExecuteMsg::Borrow {
+            denom: denom.to_string(),
+            amount: Uint128::new(100),
+            rate_mode: None,
+            duration,
+            request_id: None,
+        };
+        let out_of_bounds = ContractError::InvalidLoanDuration { min: 86_400, max: SECONDS_PER_YEAR };
+
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", Some(86_399))).unwrap_err();
+        assert_eq!(err, out_of_bounds);
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", Some(SECONDS_PER_YEAR + 1))).unwrap_err();
+        assert_eq!(err, out_of_bounds);
+
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", Some(30 * 86_400))).unwrap();
+        let loan = LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap();
+        assert_eq!(loan.term_seconds, 30 * 86_400);
+
+        // an omitted duration takes the maximum
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(100) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "usdc")), repay).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow("usdc", None)).unwrap();
+        let loan = LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap();
+        assert_eq!(loan.term_seconds, SECONDS_PER_YEAR);
+    }
+
+    #[test]
+    fn crossed_loan_bounds_are_rejected() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { min_loan_seconds: 2, max_loan_seconds: 1, ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLoanDuration { min: 2, max: 1 });
+
+        setup(deps.as_mut());
+        let update = UpdateConfigMsg { max_loan_seconds: Some(60), min_loan_seconds: Some(61), ..Default::default() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::UpdateConfig(Box::new(update))).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLoanDuration { min: 61, max: 60 });
+    }
+
+    #[test]
+    fn loan_without_term_is_never_overdue() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(10 * SECONDS_PER_YEAR);
+        let msg = QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let loan: LoanResponse = from_json(query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!((loan.is_overdue, loan.seconds_until_due), (false, None));
+    }
+
+    #[test]
+    fn healthy_loan_can_be_extended() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { loan_term_seconds: SECONDS_PER_YEAR, ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let msg = ExecuteMsg::ExtendLoan { denom: "usdc".to_string() };
+        // the accrued 10 of interest must be paid exactly
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch { denom: "usdc".to_string(), amount: Uint128::new(10) });
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(10, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(10));
+
+        let loan = LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(400));
+        assert_eq!(loan.loan_start_time, env.block.time.seconds());
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::new(400));
+    }
+
+    #[test]
+    fn refinance_snapshots_lower_rate() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(3) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        // half a year at 5% accrues 10, which must be paid exactly
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let err = exec(deps.as_mut(), env.clone(), mock_info("alice", &[]), ExecuteMsg::Refinance {}).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch { denom: "usdc".to_string(), amount: Uint128::new(10) });
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(10, "usdc")), ExecuteMsg::Refinance {}).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(10));
+
+        let loan = LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(400));
+        assert_eq!(loan.interest_rate, Decimal::percent(3));
+        assert_eq!(loan.loan_start_time, env.block.time.seconds());
+    }
+
+    #[test]
+    fn refinance_at_higher_rate_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(8) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::Refinance {}).unwrap_err();
+        assert_eq!(err, ContractError::RefinanceNotBeneficial { current: Decimal::percent(5), offered: Decimal::percent(8) });
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap().interest_rate, Decimal::percent(5));
+    }
+
+    #[test]
+    fn underwater_loan_cannot_be_extended() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // after two years 825 is owed against 800 of backing
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::ExtendLoan { denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), env, mock_info("alice", &coins(75, "usdc")), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanUnderwater {});
+    }
+
+    #[test]
+    fn liquidation_repays_only_close_factor_share() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { close_factor: Decimal::percent(50), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 825 is due after two years; one call may repay half of it, 412
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(400, "usdc")), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientFunds { denom: "usdc".to_string(), amount: Uint128::new(412) });
+
+        let res = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(825, "usdc")), msg).unwrap();
+        // 412 repaid plus a 5% bonus of 20 is seized, and the other 413 paid is refunded
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::new(BankMsg::Send { to_address: "bob".to_string(), amount: coins(432, "atom") }),
+                SubMsg::reply_always(BankMsg::Send { to_address: "bob".to_string(), amount: coins(413, "usdc") }, RELEASE_LOCK_REPLY_ID),
+            ]
+        );
+        let debt_repaid = res.attributes.iter().find(|a| a.key == "debt_repaid").unwrap();
+        assert_eq!(debt_repaid.value, "412");
+
+        // the remaining 413 stays open against the remaining 568 collateral
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(413));
+        let res = query(deps.as_ref(), env, QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(568));
+    }
+
+    #[test]
+    fn close_factor_above_one_is_rejected() {
+        let mut deps = mock_dependencies();
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), InstantiateMsg { close_factor: Decimal::percent(101), ..instantiate_msg() }).unwrap_err();
+        assert_eq!(err, ContractError::InvalidCloseFactor {});
+    }
+
+    #[test]
+    fn liquidation_pays_configured_bonus() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetLiquidationBonus { bonus: Decimal::percent(10) };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().liquidation_bonus, Decimal::percent(10));
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let res = exec(deps.as_mut(), env, mock_info("bob", &coins(825, "usdc")), msg).unwrap();
+        // 825 repaid plus a 10% bonus of 82
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "bob".to_string(), amount: coins(907, "atom") }, RELEASE_LOCK_REPLY_ID)]
+        );
+        let bonus = res.attributes.iter().find(|a| a.key == "bonus").unwrap();
+        assert_eq!(bonus.value, "82");
+    }
+
+    #[test]
+    fn healthy_self_liquidation_settles_debt_and_returns_the_rest() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::SelfLiquidate {}).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(400, "atom") }),
+                SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(600, "atom") }, RELEASE_LOCK_REPLY_ID),
+            ]
+        );
+        let written_off = res.attributes.iter().find(|a| a.key == "debt_written_off").unwrap();
+        assert_eq!(written_off.value, "0");
+        assert!(LOANS.prefix(&Addr::unchecked("alice")).range(&deps.storage, None, None, Order::Ascending).next().is_none());
+        assert!(COLLATERALS.prefix(&Addr::unchecked("alice")).range(&deps.storage, None, None, Order::Ascending).next().is_none());
+        let stats = stats(deps.as_ref());
+        assert_eq!((stats.total_borrowed, stats.total_collateral), (Uint128::zero(), Uint128::zero()));
+
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::SelfLiquidate {}).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+    }
+
+    #[test]
+    fn underwater_self_liquidation_surrenders_all_collateral_without_bonus() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // after eight years 1_050 is owed against 1_000 of collateral
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(8 * SECONDS_PER_YEAR);
+        let res = exec(deps.as_mut(), env, mock_info("alice", &[]), ExecuteMsg::SelfLiquidate {}).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(1_000, "atom") }, RELEASE_LOCK_REPLY_ID)]
+        );
+        let written_off = res.attributes.iter().find(|a| a.key == "debt_written_off").unwrap();
+        assert_eq!(written_off.value, "50");
+        let stats = stats(deps.as_ref());
+        assert_eq!((stats.total_borrowed, stats.total_bad_debt), (Uint128::zero(), Uint128::new(50)));
+    }
+
+    #[test]
+    fn self_liquidation_converts_debt_at_the_oracle_price() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(250));
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 1_000 owed at 2.5 per atom is 400 atom for the treasury
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::SelfLiquidate {}).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(400, "atom") }),
+                SubMsg::reply_always(BankMsg::Send { to_address: "alice".to_string(), amount: coins(600, "atom") }, RELEASE_LOCK_REPLY_ID),
+            ]
+        );
+        let seized = res.attributes.iter().find(|a| a.key == "collateral_seized").unwrap();
+        assert_eq!(seized.value, "1000");
+        let returned = res.attributes.iter().find(|a| a.key == "collateral_returned").unwrap();
+        assert_eq!(returned.value, "1500");
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::zero());
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn owner_info_passes_owner_checks() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let owner = owner_info(deps.as_ref(), &[]).unwrap();
+        assert_eq!(owner.sender, Addr::unchecked("owner"));
+
+        let msg = ExecuteMsg::UpdateInterestRate { new_rate: Decimal::percent(7) };
+        exec(deps.as_mut(), mock_env(), owner, msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().base_interest_rate, Decimal::percent(7));
+    }
+
+    #[test]
+    fn ping_answers_without_touching_state() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let before: Vec<_> = deps.storage.range(None, None, Order::Ascending).collect();
+
+        let res = exec(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::Ping {}).unwrap();
+        assert_eq!(res.attributes, vec![attr("action", "ping"), attr("pong", "pong"), attr("block_height", mock_env().block.height.to_string())]);
+        assert!(res.messages.is_empty());
+        let after: Vec<_> = deps.storage.range(None, None, Order::Ascending).collect();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn transfer_loan_moves_loans_and_collateral() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::TransferLoan { new_borrower: "alice2".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::AcceptLoanTransfer { from: "alice".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("alice2", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice2".to_string(), denom: "usdc".to_string() }).unwrap();
+        let loan: LoanResponse = from_json(res).unwrap();
+        assert_eq!(loan.amount_borrowed, Uint128::new(500));
+        let err = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice2".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(1_000));
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert!(collateral.collaterals.is_empty());
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::LoanCount {}).unwrap();
+        assert_eq!(from_json::<CountResponse>(res).unwrap().count, 1);
+    }
+
+    #[test]
+    fn transfer_loan_rejects_destination_with_loan() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for borrower in ["alice", "bob"] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let msg = ExecuteMsg::TransferLoan { new_borrower: "bob".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanAlreadyExists {});
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetLoan { borrower: "alice".to_string(), denom: "usdc".to_string() }).unwrap();
+        assert_eq!(from_json::<LoanResponse>(res).unwrap().amount_borrowed, Uint128::new(500));
+    }
+
+    #[test]
+    fn unaccepted_loan_transfer_leaves_the_destination_untouched() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 5_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // bob holds collateral and never agreed, so the loan stays with alice and bob can still withdraw everything
+        let msg = ExecuteMsg::TransferLoan { new_borrower: "bob".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::AcceptLoanTransfer { from: "alice".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("mallory", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::NotPendingBorrower { from: "alice".to_string() });
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("bob"), "usdc")));
+        withdraw(deps.as_mut(), "bob", 5_000).unwrap();
+    }
+
+    #[test]
+    fn accepted_loan_transfer_merges_destination_collateral() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 5_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::TransferLoan { new_borrower: "bob".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // accepting is refused while paused, then takes the loan onto bob's merged 6_000 collateral
+        let accept = ExecuteMsg::AcceptLoanTransfer { from: "alice".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::SetPaused { paused: true }).unwrap();
+        let err = exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), accept.clone()).unwrap_err();
+        assert_eq!(err, ContractError::ContractPaused {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::SetPaused { paused: false }).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), accept.clone()).unwrap();
+
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("bob"), "usdc")).unwrap().amount_borrowed, Uint128::new(750));
+        assert_eq!(COLLATERALS.load(&deps.storage, (&Addr::unchecked("bob"), "atom")).unwrap().amount, Uint128::new(6_000));
+        let err = exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), accept).unwrap_err();
+        assert_eq!(err, ContractError::NotPendingBorrower { from: "alice".to_string() });
+    }
+
+    // answer every oracle price query with `price`
+    fn mock_oracle(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, price: Decimal) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "oracle" => {
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&OraclePriceResponse { price }).unwrap()))
+            },
+            _ => SystemResult::Err(SystemError::Unknown {}),
+        });
+    }
+
+    #[test]
+    fn borrowing_power_scales_with_oracle_price() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        deposit(deps.as_mut(), "alice", 1_000);
+        assert_eq!(capacity(deps.as_ref(), "alice"), Uint128::new(1_500));
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::MAX);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_501), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(1_500) });
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        // 2_000 of collateral value at an 80% threshold backs 1_000 of debt 1.6 times
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::percent(160));
+
+        // the price fetched by the borrow is reused until it is older than price_max_age_seconds (0 here)
+        mock_oracle(&mut deps, Decimal::one());
+        let cached = PRICE_CACHE.load(&deps.storage, "atom".to_string()).unwrap();
+        assert_eq!(cached, CachedPrice { price: Decimal::percent(200), updated_at: mock_env().block.time.seconds() });
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::percent(160));
+    }
+
+    #[test]
+    fn loans_in_other_denoms_count_at_their_oracle_price() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // borrow_denom is always worth 1, so 250 uatom at 2 is worth the remaining 500 of the 1_500 limit
+        let msg = ExecuteMsg::Borrow { denom: "uatom".to_string(), amount: Uint128::new(251), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(1_500) });
+        let msg = ExecuteMsg::Borrow { denom: "uatom".to_string(), amount: Uint128::new(250), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::from_ratio(1_600u128, 1_500u128));
+    }
+
+    #[test]
+    fn liquidation_seizes_collateral_at_the_oracle_price() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // at 1.8 the collateral backs 1_440 of the 1_501 debt; 1_576 repaid plus bonus is floor(1_576 / 1.8) = 875 atom
+        mock_oracle(&mut deps, Decimal::percent(180));
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(1_501, "usdc")), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(BankMsg::Send { to_address: "bob".to_string(), amount: coins(875, "atom") }, RELEASE_LOCK_REPLY_ID)]
+        );
+        let seized = res.attributes.iter().find(|a| a.key == "collateral_seized").unwrap();
+        assert_eq!(seized.value, "1576");
+        let res = query(deps.as_ref(), env, QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(collateral.collaterals[0].amount, Uint128::new(125));
+    }
+
+    #[test]
+    fn stale_cached_price_is_refetched() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { oracle: Some("oracle".to_string()), price_max_age_seconds: 60, ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(100), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        mock_oracle(&mut deps, Decimal::percent(50));
+        let query_capacity = |deps: Deps, elapsed: u64| -> Uint128 {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(elapsed);
+            let msg = QueryMsg::BorrowCapacity { borrower: "alice".to_string() };
+            from_json::<BorrowCapacityResponse>(query(deps, env, msg).unwrap()).unwrap().capacity
+        };
+        // within the window the cached 2.0 still applies, after it the oracle's 0.5 does;
+        // either way 101 is owed once a unit of interest has accrued
+        assert_eq!(query_capacity(deps.as_ref(), 60), Uint128::new(1_399));
+        assert_eq!(query_capacity(deps.as_ref(), 61), Uint128::new(274));
+    }
+
+    #[test]
+    fn counts_track_loans_and_collaterals() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let count = |deps: Deps, msg: QueryMsg| from_json::<CountResponse>(query(deps, mock_env(), msg).unwrap()).unwrap().count;
+        assert_eq!(count(deps.as_ref(), QueryMsg::LoanCount {}), 0);
+        assert_eq!(count(deps.as_ref(), QueryMsg::CollateralCount {}), 0);
+
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 1_000);
+        assert_eq!(count(deps.as_ref(), QueryMsg::CollateralCount {}), 2);
+        for borrower in ["alice", "bob"] {
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+        assert_eq!(count(deps.as_ref(), QueryMsg::LoanCount {}), 2);
+
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(750) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(750, "usdc")), repay).unwrap();
+        assert_eq!(count(deps.as_ref(), QueryMsg::LoanCount {}), 1);
+        withdraw(deps.as_mut(), "alice", 2_000).unwrap();
+        assert_eq!(count(deps.as_ref(), QueryMsg::CollateralCount {}), 1);
+
+        // liquidation closes the loan; bob keeps the collateral left after seizure
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "bob".to_string(), denom: "usdc".to_string() };
+        exec(deps.as_mut(), env, mock_info("carol", &coins(825, "usdc")), msg).unwrap();
+        assert_eq!(count(deps.as_ref(), QueryMsg::LoanCount {}), 0);
+        assert_eq!(count(deps.as_ref(), QueryMsg::CollateralCount {}), 1);
+        withdraw(deps.as_mut(), "bob", 134).unwrap();
+        assert_eq!(count(deps.as_ref(), QueryMsg::CollateralCount {}), 0);
+    }
+
+    #[test]
+    fn liquidation_bonus_is_capped_and_owner_only() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::SetLiquidationBonus { bonus: Decimal::percent(21) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLiquidationBonus { max: Decimal::percent(20) });
+        let msg = ExecuteMsg::SetLiquidationBonus { bonus: Decimal::percent(20) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { liquidation_bonus: Decimal::percent(50), ..instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLiquidationBonus { max: Decimal::percent(20) });
+    }
+
+    #[test]
+    fn only_owner_can_pause() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::SetPaused { paused: true };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn paused_contract_rejects_gated_operations() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::SetPaused { paused: true };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let deposit_msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(10) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("bob", &coins(10, "atom")), deposit_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::ContractPaused {});
+        let err = withdraw(deps.as_mut(), "alice", 10).unwrap_err();
+        assert_eq!(err, ContractError::ContractPaused {});
+        let borrow_msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(5), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), borrow_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::ContractPaused {});
+
+        // repayment stays open so users can exit
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(500) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(500, "usdc")), msg).unwrap();
+
+        let msg = ExecuteMsg::SetPaused { paused: false };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &coins(10, "atom")), deposit_msg).unwrap();
+        withdraw(deps.as_mut(), "alice", 10).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), borrow_msg).unwrap();
+    }
+
+    #[test]
+    fn two_step_ownership_transfer() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::ProposeNewOwner { new_owner: "carol".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        // proposing alone does not hand over control
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_json(res).unwrap();
+        assert_eq!(config.owner, "owner");
+        assert_eq!(config.pending_owner, Some(Addr::unchecked("carol")));
+
+        exec(deps.as_mut(), mock_env(), mock_info("carol", &[]), ExecuteMsg::AcceptOwnership {}).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_json(res).unwrap();
+        assert_eq!(config.owner, "carol");
+        assert_eq!(config.pending_owner, None);
+
+        // the previous owner lost its permissions
+        let msg = ExecuteMsg::SetPaused { paused: true };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("carol", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn only_pending_owner_can_accept() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let err = exec(deps.as_mut(), mock_env(), mock_info("carol", &[]), ExecuteMsg::AcceptOwnership {}).unwrap_err();
+        assert_eq!(err, ContractError::NotPendingOwner {});
+
+        let msg = ExecuteMsg::ProposeNewOwner { new_owner: "carol".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let err = exec(deps.as_mut(), mock_env(), mock_info("mallory", &[]), ExecuteMsg::AcceptOwnership {}).unwrap_err();
+        assert_eq!(err, ContractError::NotPendingOwner {});
+    }
+
+    #[test]
+    fn deposit_below_minimum_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup_with(deps.as_mut(), InstantiateMsg { min_collateral_amount: Uint128::new(100), ..instantiate_msg() });
+
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(99) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(99, "atom")), msg).unwrap_err();
+        assert_eq!(err, ContractError::DepositBelowMinimum { min: Uint128::new(100) });
+
+        deposit(deps.as_mut(), "alice", 100);
+    }
+
+    #[test]
+    fn owner_adjusts_min_collateral_amount() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::SetMinCollateralAmount { amount: Uint128::new(50) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(49) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(49, "atom")), msg).unwrap_err();
+        assert_eq!(err, ContractError::DepositBelowMinimum { min: Uint128::new(50) });
+        deposit(deps.as_mut(), "alice", 50);
+    }
+
+    #[test]
+    fn deposit_overflow_returns_error() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", u128::MAX);
+
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(1) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1, "atom")), msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::Overflow(OverflowError::new(OverflowOperation::Add, Uint128::MAX, Uint128::new(1)))
+        );
+    }
+
+    #[test]
+    fn interest_overflow_returns_error() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", u128::MAX);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(u128::MAX / 2), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // principal plus a century of interest no longer fits in a Uint128
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(1) };
+        let err = exec(deps.as_mut(), env, mock_info("alice", &coins(1, "usdc")), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Overflow(_)));
+    }
+
+    #[test]
+    fn list_loans_paginates() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let borrowers = ["amy", "ben", "cat", "dan", "eve"];
+        for (i, borrower) in borrowers.iter().enumerate() {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(100 + i as u128), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let msg = QueryMsg::ListLoans { start_after: None, limit: Some(3), denom: None };
+        let page: LoansResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let names: Vec<_> = page.loans.iter().map(|(borrower, _, _)| borrower.as_str()).collect();
+        assert_eq!(names, ["amy", "ben", "cat"]);
+        assert_eq!(page.loans[1].2.amount_borrowed, Uint128::new(101));
+
+        let msg = QueryMsg::ListLoans { start_after: Some(("cat".to_string(), "usdc".to_string())), limit: Some(3), denom: None };
+        let page: LoansResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let names: Vec<_> = page.loans.iter().map(|(borrower, _, _)| borrower.as_str()).collect();
+        assert_eq!(names, ["dan", "eve"]);
+    }
+
+    #[test]
+    fn list_loans_filters_by_denom() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for (borrower, denom) in [("amy", "usdc"), ("amy", "uatom"), ("ben", "uatom"), ("cat", "usdc"), ("dan", "uatom")] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: denom.to_string(), amount: Uint128::new(100), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let list = |start_after: Option<(&str, &str)>| -> Vec<(String, String)> {
+            let start_after = start_after.map(|(borrower, denom)| (borrower.to_string(), denom.to_string()));
+            let msg = QueryMsg::ListLoans { start_after, limit: Some(2), denom: Some("uatom".to_string()) };
+            let page: LoansResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+            page.loans.into_iter().map(|(borrower, denom, _)| (borrower, denom)).collect()
+        };
+        assert_eq!(list(None), [("amy".to_string(), "uatom".to_string()), ("ben".to_string(), "uatom".to_string())]);
+        // the cursor of the last entry continues past loans in other denoms
+        assert_eq!(list(Some(("ben", "uatom"))), [("dan".to_string(), "uatom".to_string())]);
+    }
+
+    #[test]
+    fn list_collaterals_paginates() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "bob", 200);
+        deposit(deps.as_mut(), "amy", 100);
+        let msg = ExecuteMsg::DepositCollateral { token_address: "osmo".to_string(), amount: Uint128::new(150) };
+        exec(deps.as_mut(), mock_env(), mock_info("amy", &coins(150, "osmo")), msg).unwrap();
+        deposit(deps.as_mut(), "cat", 300);
+
+        let msg = QueryMsg::ListCollaterals { start_after: None, limit: Some(2) };
+        let page: CollateralsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(
+            page.collaterals,
+            vec![
+                ("amy".to_string(), "atom".to_string(), Uint128::new(100)),
+                ("amy".to_string(), "osmo".to_string(), Uint128::new(150)),
+            ]
+        );
+
+        let msg = QueryMsg::ListCollaterals { start_after: Some(("amy".to_string(), "atom".to_string())), limit: Some(2) };
+        let page: CollateralsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let keys: Vec<_> = page.collaterals.iter().map(|(owner, token, _)| (owner.as_str(), token.as_str())).collect();
+        assert_eq!(keys, [("amy", "osmo"), ("bob", "atom")]);
+
+        let msg = QueryMsg::ListCollaterals { start_after: Some(("bob".to_string(), "atom".to_string())), limit: Some(50) };
+        let page: CollateralsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(page.collaterals, vec![("cat".to_string(), "atom".to_string(), Uint128::new(300))]);
+    }
+
+    fn event_attr<'a>(res: &'a Response, ty: &str, key: &str) -> &'a str {
+        let event = res.events.iter().find(|e| e.ty == ty).unwrap();
+        &event.attributes.iter().find(|a| a.key == key).unwrap().value
+    }
+
+    #[test]
+    fn borrow_and_repay_emit_events() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let timestamp = mock_env().block.time.seconds().to_string();
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(event_attr(&res, "loan_opened", "borrower"), "alice");
+        assert_eq!(event_attr(&res, "loan_opened", "amount"), "500");
+        assert_eq!(event_attr(&res, "loan_opened", "interest_rate"), "0.05");
+        assert_eq!(event_attr(&res, "loan_opened", "timestamp"), timestamp);
+        // legacy attributes are still present
+        assert_eq!(res.attributes[0].value, "borrow");
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(500) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(500, "usdc")), msg).unwrap();
+        assert_eq!(event_attr(&res, "loan_repaid", "borrower"), "alice");
+        assert_eq!(event_attr(&res, "loan_repaid", "amount"), "500");
+        assert_eq!(event_attr(&res, "loan_repaid", "timestamp"), timestamp);
+    }
+
+    #[test]
+    fn liquidation_emits_event() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(825, "usdc")), msg).unwrap();
+        assert_eq!(event_attr(&res, "loan_liquidated", "borrower"), "alice");
+        assert_eq!(event_attr(&res, "loan_liquidated", "liquidator"), "bob");
+        assert_eq!(event_attr(&res, "loan_liquidated", "amount"), "825");
+        assert_eq!(event_attr(&res, "loan_liquidated", "interest_rate"), "0.05");
+        assert_eq!(event_attr(&res, "loan_liquidated", "timestamp"), env.block.time.seconds().to_string());
+    }
+
+    #[test]
+    fn instantiate_stores_contract_version() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let version = cw2::get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.contract, CONTRACT_NAME);
+        assert_eq!(version.version, CONTRACT_VERSION);
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Version {}).unwrap();
+        let version: VersionResponse = from_json(res).unwrap();
+        assert_eq!(version, VersionResponse { contract: CONTRACT_NAME.to_string(), version: CONTRACT_VERSION.to_string() });
+    }
+
+    #[test]
+    fn migrate_backfills_config_and_bumps_version() {
+        #[derive(Serialize, Deserialize)]
+        struct OldConfig {
+            owner: String,
+            base_interest_rate: Decimal,
+        }
+
+        let mut deps = mock_dependencies();
+        let old = OldConfig { owner: "owner".to_string(), base_interest_rate: Decimal::percent(7) };
+        Item::new("config").save(&mut deps.storage, &old).unwrap();
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.0.1").unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Version {}).unwrap();
+        assert_eq!(from_json::<VersionResponse>(res).unwrap().version, "0.0.1");
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        let version = cw2::get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.version, CONTRACT_VERSION);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Version {}).unwrap();
+        assert_eq!(from_json::<VersionResponse>(res).unwrap().version, CONTRACT_VERSION);
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.owner, "owner");
+        assert_eq!(config.base_interest_rate, Decimal::percent(7));
+        assert_eq!(config.max_ltv, DEFAULT_MAX_LTV);
+        assert_eq!(config.liquidation_threshold, DEFAULT_LIQUIDATION_THRESHOLD);
+        assert!(!config.paused);
+        assert_eq!(config.borrow_denom, DEFAULT_BORROW_DENOM);
+        assert_eq!(config.treasury, "owner");
+        assert_eq!(config.liquidation_bonus, DEFAULT_LIQUIDATION_BONUS);
+    }
+
+    #[test]
+    fn migrate_rekeys_loans_stored_per_borrower() {
+        #[derive(Serialize, Deserialize)]
+        struct OldConfig {
+            owner: String,
+            base_interest_rate: Decimal,
+        }
+        #[derive(Serialize, Deserialize)]
+        struct OldLoan {
+            amount_borrowed: Uint128,
+            interest_rate: Decimal,
+            loan_start_time: u64,
+        }
+        #[derive(Serialize, Deserialize)]
+        struct OldCollateral {
+            token_address: String,
+            amount: Uint128,
+        }
+
+        let mut deps = mock_dependencies();
+        let old = OldConfig { owner: "owner".to_string(), base_interest_rate: Decimal::percent(5) };
+        Item::new("config").save(&mut deps.storage, &old).unwrap();
+        let start = mock_env().block.time.seconds();
+        for (borrower, amount) in [("alice", 400), ("bob", 250)] {
+            let loan = OldLoan { amount_borrowed: Uint128::new(amount), interest_rate: Decimal::percent(5), loan_start_time: start };
+            Map::<&Addr, OldLoan>::new("loans").save(&mut deps.storage, &Addr::unchecked(borrower), &loan).unwrap();
+        }
+        for (owner, token, amount) in [("alice", "atom", 1_000), ("bob", "atom", 500), ("cat", "osmo", 300)] {
+            let collateral = OldCollateral { token_address: token.to_string(), amount: Uint128::new(amount) };
+            Map::<String, OldCollateral>::new("collaterals").save(&mut deps.storage, owner.to_string(), &collateral).unwrap();
+        }
+        LEGACY_TOTAL_BORROWED.save(&mut deps.storage, &Uint128::new(650)).unwrap();
+        LEGACY_PROTOCOL_RESERVES.save(&mut deps.storage, &Uint128::new(30)).unwrap();
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        // the single-denom totals move to the borrow denom's entries
+        assert_eq!(TOTAL_BORROWED.load(&deps.storage, DEFAULT_BORROW_DENOM).unwrap(), Uint128::new(650));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, DEFAULT_BORROW_DENOM).unwrap(), Uint128::new(30));
+        assert!(!LEGACY_TOTAL_BORROWED.exists(&deps.storage));
+
+        // both loans now live under the borrow denom, and nothing is left under the old keys
+        let loans = LOANS.range(&deps.storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>().unwrap();
+        let mut keys: Vec<_> = loans.iter().map(|((borrower, denom), loan)| (borrower.as_str(), denom.as_str(), loan.amount_borrowed.u128())).collect();
+        keys.sort();
+        assert_eq!(keys, vec![("alice", DEFAULT_BORROW_DENOM, 400), ("bob", DEFAULT_BORROW_DENOM, 250)]);
+        assert_eq!(LOAN_COUNT.load(&deps.storage).unwrap(), 2);
+        let msg = QueryMsg::GetLoan { borrower: "alice".to_string(), denom: DEFAULT_BORROW_DENOM.to_string() };
+        let loan: LoanResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!((loan.amount_borrowed, loan.loan_start_time), (Uint128::new(400), start));
+
+        // collateral positions move under their token, and the count and per-token totals are backfilled from them
+        let collaterals = COLLATERALS.range(&deps.storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>().unwrap();
+        let mut keys: Vec<_> = collaterals.iter().map(|((owner, token), collateral)| (owner.as_str(), token.as_str(), collateral.amount.u128())).collect();
+        keys.sort();
+        assert_eq!(keys, vec![("alice", "atom", 1_000), ("bob", "atom", 500), ("cat", "osmo", 300)]);
+        assert_eq!(COLLATERAL_COUNT.load(&deps.storage).unwrap(), 3);
+        assert_eq!(COLLATERAL_TOTALS.load(&deps.storage, "atom".to_string()).unwrap(), Uint128::new(1_500));
+        assert_eq!(COLLATERAL_TOTALS.load(&deps.storage, "osmo".to_string()).unwrap(), Uint128::new(300));
+
+        // running it again on the new layout leaves the loans and collateral untouched
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+        assert_eq!(LOANS.range(&deps.storage, None, None, Order::Ascending).count(), 2);
+        assert_eq!(COLLATERALS.range(&deps.storage, None, None, Order::Ascending).count(), 3);
+        assert_eq!(COLLATERAL_TOTALS.load(&deps.storage, "atom".to_string()).unwrap(), Uint128::new(1_500));
+    }
+
+    #[test]
+    fn migrate_rejects_downgrade() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "99.0.0").unwrap();
+
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::CannotDowngrade { stored: "99.0.0".to_string(), current: CONTRACT_VERSION.to_string() }
+        );
+    }
+
+    fn stats(deps: Deps) -> StatsResponse {
+        from_json(query(deps, mock_env(), QueryMsg::GetStats { denom: None }).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn repayment_in_interest_free_window_is_principal_only() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { grace_interest_free_seconds: SECONDS_PER_YEAR / 2, ..instantiate_msg() });
+        for borrower in ["alice", "bob"] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        // alice repays at the end of the free window
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(400) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(400, "usdc")), repay).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::zero());
+
+        // bob repays a day later and owes a day of interest, rounded up
+        env.block.time = env.block.time.plus_seconds(86_400);
+        let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(401) };
+        let res = exec(deps.as_mut(), env, mock_info("bob", &coins(401, "usdc")), repay).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(1));
+        assert!(LOANS.may_load(&deps.storage, (&Addr::unchecked("bob"), "usdc")).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_debt_is_written_off_after_collateral_is_seized() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { close_factor: Decimal::percent(95), ..instantiate_msg() });
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        // reserves of 75 absorb as much of the write-off as they can
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(75)).unwrap();
+        let write_off = ExecuteMsg::WriteOffBadDebt { borrower: "alice".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), write_off.clone()).unwrap_err();
+        assert_eq!(err, ContractError::CollateralRemaining {});
+
+        // after eight years the loan owes 1_050; repaying 95% of it (997) with the bonus takes all 1_000 of
+        // collateral and leaves 53 owed
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(8 * SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Liquidate { borrower: "alice".to_string(), denom: "usdc".to_string() };
+        exec(deps.as_mut(), env.clone(), mock_info("bob", &coins(997, "usdc")), msg).unwrap();
+        assert_eq!(stats(deps.as_ref()).total_collateral, Uint128::zero());
+
+        let err = exec(deps.as_mut(), env.clone(), mock_info("bob", &[]), write_off.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let res = exec(deps.as_mut(), env, mock_info("owner", &[]), write_off).unwrap();
+        let attr = |key: &str| res.attributes.iter().find(|a| a.key == key).unwrap().value.clone();
+        assert_eq!(attr("written_off"), "53");
+        assert_eq!(attr("reserves_used"), "53");
+        assert!(LOANS.may_load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap().is_none());
+        let stats = stats(deps.as_ref());
+        assert_eq!((stats.total_borrowed, stats.total_bad_debt), (Uint128::zero(), Uint128::new(53)));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(22));
+    }
+
+    #[test]
+    fn interest_collected_accumulates_across_repayments() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        for (borrower, amount) in [("alice", 400), ("bob", 600)] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let mut paid = Uint128::zero();
+        for (borrower, due) in [("alice", 420), ("bob", 630)] {
+            let repay = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(due) };
+            let res = exec(deps.as_mut(), env.clone(), mock_info(borrower, &coins(due, "usdc")), repay).unwrap();
+            paid += interest_paid(&res);
+        }
+        assert_eq!(paid, Uint128::new(50));
+        assert_eq!(stats(deps.as_ref()).total_interest_collected, paid);
+    }
+
+    #[test]
+    fn stats_track_total_borrowed() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::zero());
+
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 2_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(300), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::new(1_300));
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(300) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(300, "usdc")), msg).unwrap();
+        withdraw(deps.as_mut(), "alice", 400).unwrap();
+
+        let stats = stats(deps.as_ref());
+        assert_eq!(stats.total_borrowed, Uint128::new(1_000));
+        assert_eq!(stats.total_collateral, Uint128::new(2_600));
+        assert_eq!(stats.base_interest_rate, Decimal::percent(5));
+    }
+
+    #[test]
+    fn collateral_factor_scales_borrow_capacity() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "atom".to_string(), factor: Decimal::percent(75) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        // 1_000 * 0.75 factor * 0.75 ltv
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(563), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(562) });
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(562), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn borrowing_power_normalizes_token_decimals() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let owner = mock_info("owner", &[]);
+        for (token, decimals) in [("usdc", 6), ("atom", 6), ("weth", 18)] {
+            let msg = ExecuteMsg::SetTokenDecimals { token_address: token.to_string(), decimals };
+            exec(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        }
+        let msg = ExecuteMsg::AllowToken { token_address: "weth".to_string() };
+        exec(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "weth".to_string(), factor: Decimal::one() };
+        exec(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+
+        // 1 atom (6 decimals) plus 2 weth (18 decimals) is worth 3 usdc-scale units of 10^6
+        deposit(deps.as_mut(), "alice", 1_000_000);
+        let weth = 2_000_000_000_000_000_000u128;
+        let msg = ExecuteMsg::DepositCollateral { token_address: "weth".to_string(), amount: Uint128::new(weth) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(weth, "weth")), msg).unwrap();
+        assert_eq!(collateral_value(deps.as_ref(), &CONFIG.load(&deps.storage).unwrap(), &Addr::unchecked("alice"), mock_env().block.time.seconds()).unwrap(), Uint128::new(3_000_000));
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(2_250_001), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::new(2_250_000) });
+
+        // the health factor values the weth at the same 2 units, not at its raw 2 * 10^18
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(2_250_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::from_ratio(2_400_000u128, 2_250_000u128));
+
+        let msg = ExecuteMsg::SetTokenDecimals { token_address: "weth".to_string(), decimals: 19 };
+        let err = exec(deps.as_mut(), mock_env(), owner, msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDecimals { max: 18 });
+    }
+
+    #[test]
+    fn collateral_without_factor_has_no_borrowing_power() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::DepositCollateral { token_address: "osmo".to_string(), amount: Uint128::new(1_000) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1_000, "osmo")), msg).unwrap();
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LtvExceeded { max_borrow: Uint128::zero() });
+
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "osmo".to_string(), factor: Decimal::percent(101) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidCollateralFactor {});
+    }
+
+    #[test]
+    fn only_whitelisted_tokens_can_be_deposited() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        // allowed token
+        deposit(deps.as_mut(), "alice", 100);
+
+        // token that was never allowed
+        let msg = ExecuteMsg::DepositCollateral { token_address: "junk".to_string(), amount: Uint128::new(100) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "junk")), msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenNotAllowed { token_address: "junk".to_string() });
+
+        // token removed from the whitelist
+        let msg = ExecuteMsg::DisallowToken { token_address: "atom".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(100) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "atom")), msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenNotAllowed { token_address: "atom".to_string() });
+    }
+
+    #[test]
+    fn only_owner_can_manage_whitelist() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::AllowToken { token_address: "junk".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let msg = ExecuteMsg::DisallowToken { token_address: "atom".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn cw20_receive_records_collateral_and_withdraw_transfers_back() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let msg = ExecuteMsg::AllowToken { token_address: "cw20token".to_string() };
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(500),
+            msg: to_json_binary(&ReceiveMsg::DepositCollateral {}).unwrap(),
+        });
+        exec(deps.as_mut(), mock_env(), mock_info("cw20token", &[]), msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetCollateral { borrower: "alice".to_string() }).unwrap();
+        let collateral: CollateralResponse = from_json(res).unwrap();
+        assert_eq!(
+            collateral.collaterals,
+            vec![Collateral { token_address: "cw20token".to_string(), amount: Uint128::new(500), cw20: true, deposited_at: mock_env().block.time.seconds() }]
+        );
+
+        let msg = ExecuteMsg::WithdrawCollateral { token_address: "cw20token".to_string(), amount: Uint128::new(200) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let transfer = Cw20ExecuteMsg::Transfer { recipient: "alice".to_string(), amount: Uint128::new(200) };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_always(WasmMsg::Execute {
+                contract_addr: "cw20token".to_string(),
+                msg: to_json_binary(&transfer).unwrap(),
+                funds: vec![],
+            }, WITHDRAW_REPLY_ID)]
+        );
+    }
+
+    #[test]
+    fn cw20_receive_from_unlisted_token_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(500),
+            msg: to_json_binary(&ReceiveMsg::DepositCollateral {}).unwrap(),
+        });
+        let err = exec(deps.as_mut(), mock_env(), mock_info("fake", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenNotAllowed { token_address: "fake".to_string() });
+    }
+
+    #[test]
+    fn repay_before_term_charges_normal_interest() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { loan_term_seconds: SECONDS_PER_YEAR, penalty_rate: Decimal::percent(10), ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(2_000_000) };
+        let res = exec(deps.as_mut(), env, mock_info("alice", &coins(2_000_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(25_000));
+    }
+
+    #[test]
+    fn repay_after_term_adds_penalty_interest() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { loan_term_seconds: SECONDS_PER_YEAR / 2, penalty_rate: Decimal::percent(10), ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // a full year: 5% normal interest, plus 10% penalty on the half year past the term
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(2_000_000) };
+        let res = exec(deps.as_mut(), env, mock_info("alice", &coins(2_000_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(50_000 + 50_000));
+    }
+
+    #[test]
+    fn repayments_accumulate_reserves() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { reserve_factor: Decimal::percent(10), ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // a year in, 50_000 interest is due; pay 30_000 of it, 10% goes to reserves
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(30_000) };
+        let res = exec(deps.as_mut(), env.clone(), mock_info("alice", &coins(30_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(30_000));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(3_000));
+
+        // the remaining 1_020_000 accrues 51_000 over the next year
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(2_000_000) };
+        let res = exec(deps.as_mut(), env, mock_info("alice", &coins(2_000_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::new(51_000));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(3_000 + 5_100));
+    }
+
+    #[test]
+    fn only_owner_changes_treasury() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(1_000)).unwrap();
+        let update = |treasury: &str| ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg { treasury: Some(treasury.to_string()), ..UpdateConfigMsg::default() }));
+
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), update("alice")).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("vault")).unwrap();
+
+        let msg = ExecuteMsg::WithdrawReserves { amount: Uint128::new(400), denom: None };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send { to_address: "vault".to_string(), amount: coins(400, "usdc") })]
+        );
+    }
+
+    #[test]
+    fn reserves_query_reports_interest_cut() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { reserve_factor: Decimal::percent(20), ..instantiate_msg() });
+        let reserves = |deps: Deps| -> ReservesResponse { from_json(query(deps, mock_env(), QueryMsg::Reserves { denom: None }).unwrap()).unwrap() };
+        assert_eq!(reserves(deps.as_ref()).reserves, Uint128::zero());
+
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(1_050_000) };
+        exec(deps.as_mut(), env, mock_info("alice", &coins(1_050_000, "usdc")), msg).unwrap();
+
+        // 20% of 50_000 interest
+        let res = reserves(deps.as_ref());
+        assert_eq!(res.reserves, Uint128::new(10_000));
+        assert_eq!(res.reserve_factor, Decimal::percent(20));
+    }
+
+    #[test]
+    fn only_owner_withdraws_reserves() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(1_000)).unwrap();
+
+        let msg = ExecuteMsg::WithdrawReserves { amount: Uint128::new(400), denom: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(400, "usdc") })]
+        );
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(600));
+
+        let msg = ExecuteMsg::WithdrawReserves { amount: Uint128::new(601), denom: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientReserves { available: Uint128::new(600) });
+    }
+
+    #[test]
+    fn borrow_rate_follows_utilization() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { slope: Decimal::percent(20), ..instantiate_msg() });
+        let config = CONFIG.load(&deps.storage).unwrap();
+
+        // nothing lent out yet, the rate is the base rate
+        TOTAL_LIQUIDITY.save(&mut deps.storage, "usdc", &Uint128::new(10_000)).unwrap();
+        assert_eq!(current_borrow_rate(deps.as_ref(), &config, "usdc").unwrap(), Decimal::percent(5));
+
+        deposit(deps.as_mut(), "alice", 10_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(2_500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        // 25% utilized: 5% + 20% * 25%
+        assert_eq!(current_borrow_rate(deps.as_ref(), &config, "usdc").unwrap(), Decimal::percent(10));
+
+        deposit(deps.as_mut(), "bob", 10_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(5_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        // bob's loan was priced at 25% utilization, the pool is now 75% utilized
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap().interest_rate, Decimal::percent(5));
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("bob"), "usdc")).unwrap().interest_rate, Decimal::percent(10));
+        assert_eq!(current_borrow_rate(deps.as_ref(), &config, "usdc").unwrap(), Decimal::percent(20));
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(10_000) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(10_000, "usdc")), msg.clone()).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &coins(10_000, "usdc")), msg).unwrap();
+        assert_eq!(current_borrow_rate(deps.as_ref(), &config, "usdc").unwrap(), Decimal::percent(5));
+    }
+
+    #[test]
+    fn current_borrow_rate_query_matches_snapshotted_rate() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { slope: Decimal::percent(20), ..instantiate_msg() });
+        let msg = ExecuteMsg::ProvideLiquidity {};
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &coins(10_000, "usdc")), msg).unwrap();
+        deposit(deps.as_mut(), "alice", 10_000);
+        deposit(deps.as_mut(), "bob", 10_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(4_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let quote: BorrowRateResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::CurrentBorrowRate { borrower: None, denom: None }).unwrap()).unwrap();
+        // 40% utilized: 5% + 20% * 40%
+        assert_eq!(quote, BorrowRateResponse { rate: Decimal::percent(13), utilization: Decimal::percent(40) });
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("bob"), "usdc")).unwrap().interest_rate, quote.rate);
+    }
+
+    #[test]
+    fn borrow_denom_collateral_is_not_lent_out() {
+        // the contract holds alice's 1_000 usdc of collateral plus 500 usdc of liquidity
+        let mut deps = mock_dependencies_with_balance(&coins(1_500, "usdc"));
+        setup(deps.as_mut());
+        let owner = mock_info("owner", &[]);
+        exec(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::AllowToken { token_address: "usdc".to_string() }).unwrap();
+        let msg = ExecuteMsg::SetCollateralFactor { token_address: "usdc".to_string(), factor: Decimal::one() };
+        exec(deps.as_mut(), mock_env(), owner, msg).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &coins(500, "usdc")), ExecuteMsg::ProvideLiquidity {}).unwrap();
+        let msg = ExecuteMsg::DepositCollateral { token_address: "usdc".to_string(), amount: Uint128::new(1_000) };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1_000, "usdc")), msg).unwrap();
+
+        let borrow = |amount: u128| ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow(501)).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientLiquidity { available: Uint128::new(500) });
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), borrow(500)).unwrap();
+
+        // the pool is fully lent out, and the collateral counts once towards health
+        assert_eq!(utilization(deps.as_ref(), "usdc").unwrap(), Decimal::one());
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::percent(160));
+        assert_eq!(COLLATERAL_TOTALS.load(&deps.storage, "usdc".to_string()).unwrap(), Uint128::new(1_000));
+    }
+
+    #[test]
+    fn provided_liquidity_drives_utilization() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { slope: Decimal::percent(20), ..instantiate_msg() });
+        let config = CONFIG.load(&deps.storage).unwrap();
+
+        let msg = ExecuteMsg::ProvideLiquidity {};
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &coins(10_000, "usdc")), msg).unwrap();
+        assert_eq!(res.attributes.iter().find(|a| a.key == "utilization").unwrap().value, "0");
+        assert_eq!(TOTAL_LIQUIDITY.load(&deps.storage, "usdc").unwrap(), Uint128::new(10_000));
+
+        deposit(deps.as_mut(), "alice", 10_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(5_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(utilization(deps.as_ref(), "usdc").unwrap(), Decimal::percent(50));
+        assert_eq!(current_borrow_rate(deps.as_ref(), &config, "usdc").unwrap(), Decimal::percent(15));
+
+        // 借出中的部分不可取回
+        let msg = ExecuteMsg::RemoveLiquidity { amount: Uint128::new(5_001), denom: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientLiquidity { available: Uint128::new(5_000) });
+
+        let msg = ExecuteMsg::RemoveLiquidity { amount: Uint128::new(2_500), denom: None };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send { to_address: "owner".to_string(), amount: coins(2_500, "usdc") })]);
+        assert_eq!(res.attributes.iter().find(|a| a.key == "utilization").unwrap().value, "0.666666666666666666");
+        assert_eq!(TOTAL_LIQUIDITY.load(&deps.storage, "usdc").unwrap(), Uint128::new(7_500));
+    }
+
+    #[test]
+    fn provide_liquidity_reports_lower_borrow_rate() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { slope: Decimal::percent(20), ..instantiate_msg() });
+        let provide = |deps: DepsMut| {
+            let res = exec(deps, mock_env(), mock_info("owner", &coins(10_000, "usdc")), ExecuteMsg::ProvideLiquidity {}).unwrap();
+            res.attributes.iter().find(|a| a.key == "current_borrow_rate").unwrap().value.parse::<Decimal>().unwrap()
+        };
+        assert_eq!(provide(deps.as_mut()), Decimal::percent(5));
+        deposit(deps.as_mut(), "alice", 10_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(5_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // 50% utilized quotes 15%; doubling the pool to 25% utilization brings it down to 10%
+        let quote: BorrowRateResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::CurrentBorrowRate { borrower: None, denom: None }).unwrap()).unwrap();
+        assert_eq!(quote.rate, Decimal::percent(15));
+        let rate = provide(deps.as_mut());
+        assert!(rate < quote.rate);
+        assert_eq!(rate, Decimal::percent(10));
+    }
+
+    #[test]
+    fn liquidity_is_owner_only_and_kept_per_denom() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let msg = ExecuteMsg::ProvideLiquidity {};
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(100, "usdc")), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[coin(100, "usdc"), coin(100, "uatom")]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLiquidityDeposit {});
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLiquidityDeposit {});
+
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &coins(100, "uatom")), msg).unwrap();
+        assert_eq!(TOTAL_LIQUIDITY.load(&deps.storage, "uatom").unwrap(), Uint128::new(100));
+        assert!(!TOTAL_LIQUIDITY.has(&deps.storage, "usdc"));
+        // usdc has no liquidity of its own to take back
+        let msg = ExecuteMsg::RemoveLiquidity { amount: Uint128::new(1), denom: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientLiquidity { available: Uint128::zero() });
+        let msg = ExecuteMsg::RemoveLiquidity { amount: Uint128::new(100), denom: Some("uatom".to_string()) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send { to_address: "owner".to_string(), amount: coins(100, "uatom") })]);
+
+        let msg = ExecuteMsg::RemoveLiquidity { amount: Uint128::new(1), denom: None };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+
+    #[test]
+    fn nested_call_while_locked_is_rejected() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        deposit(deps.as_mut(), "bob", 1_000);
+
+        // the payout has been dispatched but has not replied yet
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let err = withdraw(deps.as_mut(), "alice", 100).unwrap_err();
+        assert_eq!(err, ContractError::Reentrancy {});
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(100), rate_mode: None, duration: None, request_id: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Reentrancy {});
+
+        let result = SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None });
+        reply(deps.as_mut(), mock_env(), Reply { id: res.messages[0].id, result }).unwrap();
+        withdraw(deps.as_mut(), "alice", 100).unwrap();
+    }
+
+    #[test]
+    fn failed_transfer_reply_aborts() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        REENTRANCY_LOCK.save(&mut deps.storage, &true).unwrap();
+
+        let result = SubMsgResult::Err("insufficient funds".to_string());
+        let err = reply(deps.as_mut(), mock_env(), Reply { id: RELEASE_LOCK_REPLY_ID, result }).unwrap_err();
+        assert_eq!(err, ContractError::Std(StdError::generic_err("insufficient funds")));
+    }
+
+    #[test]
+    fn failed_payout_rolls_back_borrow() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+        let result = SubMsgResult::Err("insufficient funds".to_string());
+        reply(deps.as_mut(), mock_env(), Reply { id: res.messages[0].id, result }).unwrap();
+
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::zero());
+        // the lock was released, so alice can try again
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn failed_transfer_rolls_back_withdraw() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+
+        let msg = ExecuteMsg::WithdrawCollateral { token_address: "atom".to_string(), amount: Uint128::new(1_000) };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let result = SubMsgResult::Err("send failed".to_string());
+        reply(deps.as_mut(), mock_env(), Reply { id: res.messages[0].id, result }).unwrap();
+
+        let collateral = COLLATERALS.load(&deps.storage, (&Addr::unchecked("alice"), "atom")).unwrap();
+        assert_eq!(collateral.amount, Uint128::new(1_000));
+        assert_eq!(stats(deps.as_ref()).total_collateral, Uint128::new(1_000));
+    }
+
+    #[test]
+    fn repay_requires_matching_funds() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(200) };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch { denom: "usdc".to_string(), amount: Uint128::new(200) });
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(200, "atom")), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch { denom: "usdc".to_string(), amount: Uint128::new(200) });
+
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(200, "usdc")), msg).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(LOANS.load(&deps.storage, (&Addr::unchecked("alice"), "usdc")).unwrap().amount_borrowed, Uint128::new(300));
+    }
+
+    #[test]
+    fn repay_overpayment_is_refunded() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(800) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(800, "usdc")), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send { to_address: "alice".to_string(), amount: coins(300, "usdc") })]
+        );
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+    }
+
+    #[test]
+    fn batch_repay_clears_all_loans() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        let borrowers = ["amy", "ben", "cat"];
+        for borrower in borrowers {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let repayments = borrowers.iter().map(|b| (b.to_string(), Uint128::new(500))).collect();
+        let msg = ExecuteMsg::BatchRepay { denom: "usdc".to_string(), repayments };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("keeper", &coins(1_500, "usdc")), msg).unwrap();
+        assert_eq!(res.events.iter().filter(|e| e.ty == "loan_repaid").count(), 3);
+        for borrower in borrowers {
+            assert!(!LOANS.has(&deps.storage, (&Addr::unchecked(borrower), "usdc")));
+        }
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::zero());
+    }
+
+    #[test]
+    fn batch_repay_rejects_invalid_entry_and_oversized_batch() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "amy", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("amy", &[]), msg).unwrap();
+
+        let repayments = vec![("amy".to_string(), Uint128::new(500)), ("ben".to_string(), Uint128::new(1))];
+        let err = exec(deps.as_mut(), mock_env(), mock_info("keeper", &coins(501, "usdc")), ExecuteMsg::BatchRepay { denom: "usdc".to_string(), repayments }).unwrap_err();
+        assert_eq!(err, ContractError::NoActiveLoan {});
+
+        let repayments = vec![("amy".to_string(), Uint128::new(1)); MAX_BATCH_SIZE + 1];
+        let err = exec(deps.as_mut(), mock_env(), mock_info("keeper", &[]), ExecuteMsg::BatchRepay { denom: "usdc".to_string(), repayments }).unwrap_err();
+        assert_eq!(err, ContractError::BatchTooLarge { max: MAX_BATCH_SIZE as u32 });
+    }
+
+    #[test]
+    fn stale_collateral_recovered_only_after_grace_window() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { loan_term_seconds: 1_000, recovery_grace_seconds: 500, ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let recover = ExecuteMsg::RecoverStaleCollateral { borrower: "alice".to_string() };
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1_500);
+        let err = exec(deps.as_mut(), env.clone(), mock_info("owner", &[]), recover.clone()).unwrap_err();
+        assert_eq!(err, ContractError::LoanNotStale {});
+
+        env.block.time = env.block.time.plus_seconds(1);
+        let err = exec(deps.as_mut(), env.clone(), mock_info("bob", &[]), recover.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        let res = exec(deps.as_mut(), env, mock_info("owner", &[]), recover).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(1_000, "atom") })]
+        );
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        let written_off = res.attributes.iter().find(|a| a.key == "debt_written_off").unwrap();
+        assert_eq!(written_off.value, "0");
+        let stats = stats(deps.as_ref());
+        assert_eq!(stats.total_borrowed, Uint128::zero());
+        assert_eq!(stats.total_collateral, Uint128::zero());
+        assert_eq!(stats.total_bad_debt, Uint128::zero());
+    }
+
+    #[test]
+    fn stale_recovery_writes_off_what_the_collateral_does_not_cover() {
+        let mut deps = funded_deps();
+        let msg = InstantiateMsg { oracle: Some("oracle".to_string()), loan_term_seconds: 1_000, ..instantiate_msg() };
+        setup_with(deps.as_mut(), msg);
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(100)).unwrap();
+        mock_oracle(&mut deps, Decimal::one());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(700), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // the 1_000 atom recovered are only worth 500 against the 701 owed
+        mock_oracle(&mut deps, Decimal::percent(50));
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1_001);
+        let res = exec(deps.as_mut(), env, mock_info("owner", &[]), ExecuteMsg::RecoverStaleCollateral { borrower: "alice".to_string() }).unwrap();
+        let written_off = res.attributes.iter().find(|a| a.key == "debt_written_off").unwrap();
+        assert_eq!(written_off.value, "201");
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::new(201));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::zero());
+    }
+
+    #[test]
+    fn owner_closes_dust_loans_only() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { dust_threshold: Uint128::new(10), ..instantiate_msg() });
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(100)).unwrap();
+        for (borrower, amount) in [("amy", 10), ("ben", 9)] {
+            deposit(deps.as_mut(), borrower, 1_000);
+            let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(amount), rate_mode: None, duration: None, request_id: None };
+            exec(deps.as_mut(), mock_env(), mock_info(borrower, &[]), msg).unwrap();
+        }
+
+        let msg = ExecuteMsg::CloseDustLoan { borrower: "amy".to_string(), denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::LoanNotDust { threshold: Uint128::new(10) });
+
+        let msg = ExecuteMsg::CloseDustLoan { borrower: "ben".to_string(), denom: "usdc".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("ben", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("ben"), "usdc")));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(91));
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::new(10));
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::new(9));
+    }
+
+    #[test]
+    fn dust_loans_in_other_denoms_are_written_off_at_their_value() {
+        let mut deps = funded_deps();
+        setup_with(deps.as_mut(), InstantiateMsg { dust_threshold: Uint128::new(10), oracle: Some("oracle".to_string()), ..instantiate_msg() });
+        mock_oracle(&mut deps, Decimal::percent(200));
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(17)).unwrap();
+        deposit(deps.as_mut(), "ben", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "uatom".to_string(), amount: Uint128::new(9), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("ben", &[]), msg).unwrap();
+
+        // 9 uatom is worth 18 usdc, more than the reserves hold
+        let msg = ExecuteMsg::CloseDustLoan { borrower: "ben".to_string(), denom: "uatom".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientReserves { available: Uint128::new(17) });
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::zero());
+
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(20)).unwrap();
+        exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("ben"), "uatom")));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(2));
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::new(18));
+    }
+
+    #[test]
+    fn owner_repays_loan_from_reserves() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(600)).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::RepayFromReserves { borrower: "alice".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+
+        // a year later 525 is due
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        exec(deps.as_mut(), env, mock_info("owner", &[]), msg).unwrap();
+        assert!(!LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(75));
+        assert_eq!(stats(deps.as_ref()).total_borrowed, Uint128::zero());
+        // the debt nobody repaid is recorded as written off
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::new(525));
+    }
+
+    #[test]
+    fn repay_from_reserves_requires_enough_reserves() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        PROTOCOL_RESERVES.save(&mut deps.storage, "usdc", &Uint128::new(499)).unwrap();
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(500), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::RepayFromReserves { borrower: "alice".to_string() };
+        let err = exec(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::InsufficientReserves { available: Uint128::new(499) });
+        assert!(LOANS.has(&deps.storage, (&Addr::unchecked("alice"), "usdc")));
+        assert_eq!(PROTOCOL_RESERVES.load(&deps.storage, "usdc").unwrap(), Uint128::new(499));
+        assert_eq!(stats(deps.as_ref()).total_bad_debt, Uint128::zero());
+    }
+
+    fn health(deps: Deps, env: Env, borrower: &str) -> Decimal {
+        let msg = QueryMsg::HealthFactor { borrower: borrower.to_string() };
+        let res: HealthFactorResponse = from_json(query(deps, env, msg).unwrap()).unwrap();
+        res.health_factor
+    }
+
+    #[test]
+    fn health_factor_reflects_position() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::MAX);
+
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(400), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        // 1_000 * 0.8 / 400
+        assert_eq!(health(deps.as_ref(), mock_env(), "alice"), Decimal::percent(200));
+
+        deposit(deps.as_mut(), "bob", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        // debt grows to 800 after 1.33 years, leaving the position right at the threshold
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR * 4 / 3);
+        let factor = health(deps.as_ref(), env, "bob");
+        assert!(factor > Decimal::percent(99) && factor <= Decimal::percent(101));
+    }
+
+    #[test]
+    fn top_up_reports_improved_health_factor() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 1_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(750), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let before = health(deps.as_ref(), mock_env(), "alice");
+        // 1_000 * 0.8 / 750
+        assert!(before < Decimal::percent(107));
+
+        let msg = ExecuteMsg::DepositCollateral { token_address: "atom".to_string(), amount: Uint128::new(500) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(500, "atom")), msg).unwrap();
+        let attr = res.attributes.iter().find(|a| a.key == "health_factor").unwrap();
+        // 1_500 * 0.8 / 750
+        assert_eq!(attr.value, "1.6");
+        assert!(attr.value.parse::<Decimal>().unwrap() > before);
+    }
+
+    fn interest_paid(res: &Response) -> Uint128 {
+        let attr = res.attributes.iter().find(|a| a.key == "interest_paid").unwrap();
+        attr.value.parse::<u128>().unwrap().into()
+    }
+
+    #[test]
+    fn interest_is_prorated_by_elapsed_time() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+
+        deposit(deps.as_mut(), "alice", 2_000_000);
+        let principal = Uint128::new(1_200_000);
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: principal, rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        // repay 30 days later
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(30 * 24 * 60 * 60);
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(2_000_000) };
+        let res = exec(deps.as_mut(), env, mock_info("alice", &coins(2_000_000, "usdc")), msg).unwrap();
+
+        // 30 days of 5% is 1_200_000 * 0.05 * 30 / 365 = 4931.5, rounded up, roughly 5%/12 of principal
+        let interest = interest_paid(&res);
+        assert_eq!(interest, Uint128::new(4932));
+        let monthly = principal * Decimal::percent(5) * Decimal::from_ratio(1u128, 12u128);
+        assert!(interest < monthly && interest > monthly * Decimal::percent(95));
+    }
+
+    #[test]
+    fn repay_in_same_block_owes_no_interest() {
+        let mut deps = funded_deps();
+        setup(deps.as_mut());
+        deposit(deps.as_mut(), "alice", 2_000);
+
+        let msg = ExecuteMsg::Borrow { denom: "usdc".to_string(), amount: Uint128::new(1_000), rate_mode: None, duration: None, request_id: None };
+        exec(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::RepayLoan { denom: "usdc".to_string(), amount: Uint128::new(1_000) };
+        let res = exec(deps.as_mut(), mock_env(), mock_info("alice", &coins(1_000, "usdc")), msg).unwrap();
+        assert_eq!(interest_paid(&res), Uint128::zero());
+    }
+
+    // property tests for the interest and repayment math
+    mod proptests {
+        use super::*;
+        use cosmwasm_std::testing::MockStorage;
+        use proptest::prelude::*;
+
+        const START: u64 = 1_000;
+
+        // a config with a loan term, so penalties past the term are exercised too
+        fn config() -> Config {
+            let mut deps = mock_dependencies();
+            let msg = InstantiateMsg { loan_term_seconds: SECONDS_PER_YEAR, penalty_rate: Decimal::percent(20), ..instantiate_msg() };
+            setup_with(deps.as_mut(), msg);
+            CONFIG.load(&deps.storage).unwrap()
+        }
+
+        fn loan(principal: u128, rate_bps: u64) -> LoanInfo {
+            LoanInfo {
+                amount_borrowed: Uint128::new(principal),
+                interest_rate: Decimal::from_ratio(rate_bps, 10_000u64),
+                loan_start_time: START,
+                loan_start_height: 0,
+                rate_mode: RateMode::Fixed,
+                interest_free_until: 0,
+                term_seconds: 0,
+                term_start_time: 0,
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn total_due_never_below_principal(principal in 0u128..1_000_000_000_000_000_000, rate_bps in 0u64..=10_000, elapsed in 0u64..SECONDS_PER_YEAR * 10) {
+                let loan = loan(principal, rate_bps);
+                let due = total_due(&loan, &config(), START + elapsed, 0).unwrap();
+                prop_assert!(due >= loan.amount_borrowed);
+            }
+
+            #[test]
+            fn interest_is_monotonic_in_elapsed_time(principal in 0u128..1_000_000_000_000_000_000, rate_bps in 0u64..=10_000, a in 0u64..SECONDS_PER_YEAR * 10, b in 0u64..SECONDS_PER_YEAR * 10) {
+                let (earlier, later) = (a.min(b), a.max(b));
+                let loan = loan(principal, rate_bps);
+                let config = config();
+                prop_assert!(total_due(&loan, &config, START + earlier, 0).unwrap() <= total_due(&loan, &config, START + later, 0).unwrap());
+            }
+
+            #[test]
+            fn repaying_total_due_clears_the_loan(principal in 1u128..1_000_000_000_000_000_000, rate_bps in 0u64..=10_000, elapsed in 0u64..SECONDS_PER_YEAR * 10) {
+                let mut storage = MockStorage::new();
+                let config = config();
+                let borrower = Addr::unchecked("alice");
+                let loan = loan(principal, rate_bps);
+                LOANS.save(&mut storage, (&borrower, "usdc"), &loan).unwrap();
+                TOTAL_BORROWED.save(&mut storage, "usdc", &loan.amount_borrowed).unwrap();
+
+                let now = START + elapsed;
+                let due = total_due(&loan, &config, now, 0).unwrap();
+                let repayment = apply_repayment(&mut storage, &config, &borrower, "usdc", due, now, 0).unwrap();
+                prop_assert!(!LOANS.has(&storage, (&borrower, "usdc")));
+                prop_assert_eq!(repayment.refund, Uint128::zero());
+                prop_assert_eq!(repayment.interest_paid, due - loan.amount_borrowed);
+                prop_assert_eq!(TOTAL_BORROWED.load(&storage, "usdc").unwrap(), Uint128::zero());
+            }
+        }
+    }
+}
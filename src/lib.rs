@@ -3,6 +3,6 @@ mod error;
 pub mod helpers;
 pub mod msg;
 pub mod state;
-mod lending;
+pub mod lending;
 
 pub use crate::error::ContractError;